@@ -117,6 +117,11 @@ interface MetadataItem {
   sbcs: string;
   url: string;
   hello_world?: any;
+  aliases?: string[];
+  deprecated_aliases?: string[];
+  // filled in by the server, once it has probed the language's image
+  detected_version?: string;
+  versions?: Record<string, { image: string; detected_version?: string }>;
 }
 
 async function getMetadata() {
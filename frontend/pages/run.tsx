@@ -317,10 +317,21 @@ function _Run(
   }, [router, shouldLoad, codeBox]);
 
   if (languages && language && !languages[language]) {
-    alert(`Unknown language:\n${language}`);
-    setLanguage(null);
-    setLanguageSelectorOpen(true);
-    language = null;
+    // links to languages which have since been renamed still work
+    const alias = language;
+    const renamed = Object.keys(languages).find((id) => [
+      ...languages[id].aliases ?? [],
+      ...languages[id].deprecated_aliases ?? [],
+    ].includes(alias));
+    if (renamed) {
+      setLanguage(renamed);
+      language = renamed;
+    } else {
+      alert(`Unknown language:\n${language}`);
+      setLanguage(null);
+      setLanguageSelectorOpen(true);
+      language = null;
+    }
   }
 
   // combination of useRef and useMemo: useRef ignores its argument after the second call, but the
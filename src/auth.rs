@@ -0,0 +1,154 @@
+use crate::{Error, egress, languages::LanguageLimits, ratelimit, shared};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The permissions and limits which apply to requests made with an API key.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    /// the key itself; filled in from the key file's map keys
+    #[serde(skip)]
+    pub token: String,
+    /// a description of who the key belongs to, for the benefit of humans reading the key file
+    #[allow(dead_code)]
+    pub name: String,
+    /// maximum number of requests which can be made per day (UTC), or unlimited if not given
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+    /// maximum timeout which can be requested, in seconds (see LanguageLimits::max_timeout)
+    #[serde(default)]
+    pub max_timeout: Option<i32>,
+    /// maximum memory limit which can be requested, in MiB (see LanguageLimits::max_memory)
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    /// maximum number of processes and threads which can be requested (see LanguageLimits::max_pids)
+    #[serde(default)]
+    pub max_pids: Option<u64>,
+    /// languages which can be used, or all languages if not given
+    #[serde(default)]
+    pub languages: Option<HashSet<String>>,
+    /// whether the custom_runner request field can be used
+    #[serde(default = "default_custom_runners")]
+    pub custom_runners: bool,
+    /// overrides the server-wide rate limit for this key
+    #[serde(default)]
+    pub rate_limit: Option<ratelimit::Limit>,
+    /// allows outbound network access through the egress proxy, for any language
+    #[serde(default)]
+    pub egress: Option<egress::Policy>,
+}
+
+fn default_custom_runners() -> bool {
+    ANONYMOUS.custom_runners
+}
+
+/// the limits which apply to requests made without an API key
+pub static ANONYMOUS: ApiKey = ApiKey {
+    token: String::new(),
+    name: String::new(),
+    daily_quota: None,
+    max_timeout: None,
+    max_memory: None,
+    max_pids: None,
+    languages: None,
+    custom_runners: true,
+    rate_limit: None,
+    egress: None,
+};
+
+fn load_keys() -> HashMap<String, ApiKey> {
+    let path = match std::env::var("ATO_API_KEYS") {
+        Ok(path) => path,
+        // no API keys are configured, so all requests are anonymous
+        Err(std::env::VarError::NotPresent) => return HashMap::new(),
+        Err(std::env::VarError::NotUnicode(_)) => panic!("$ATO_API_KEYS is invalid Unicode"),
+    };
+    let data =
+        std::fs::read(&path).unwrap_or_else(|e| panic!("error reading API key file {path}: {e}"));
+    let mut keys: HashMap<String, ApiKey> = serde_json::from_slice(&data)
+        .unwrap_or_else(|e| panic!("API key file {path} is invalid: {e}"));
+    for (token, key) in keys.iter_mut() {
+        key.token = token.clone();
+    }
+    keys
+}
+
+/// API keys loaded from the file at $ATO_API_KEYS, indexed by token
+pub static KEYS: LazyLock<HashMap<String, ApiKey>> = LazyLock::new(load_keys);
+
+/// Find the API key given in an `Authorization: Bearer <token>` header.
+pub fn authenticate(authorization: &str) -> Option<&'static ApiKey> {
+    let token = authorization.strip_prefix("Bearer ")?.trim();
+    KEYS.get(token)
+}
+
+/// The largest timeout (in seconds), memory limit (in MiB) and number of processes which any client
+/// is allowed to ask for in a language.
+pub fn max_limits(language: &LanguageLimits) -> (i32, u64, u64) {
+    let keys = || std::iter::once(&ANONYMOUS).chain(KEYS.values());
+    (
+        keys().map(|key| language.max_timeout(key)).max().unwrap(),
+        keys().map(|key| language.max_memory(key)).max().unwrap(),
+        keys().map(|key| language.max_pids(key)).max().unwrap(),
+    )
+}
+
+/// number of API keys whose usage can be tracked at once
+const TABLE_SIZE: usize = 1024;
+
+/// Usage counts for daily quotas, which live in shared memory.
+///
+/// Note that these are lost when the server is restarted.
+pub struct QuotaState {
+    usage: shared::Table<Usage, TABLE_SIZE>,
+}
+
+struct Usage {
+    // the day, as a number of days since the Unix epoch, which the count applies to
+    day: u64,
+    count: u64,
+}
+
+fn today() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970");
+    now.as_secs() / (24 * 60 * 60)
+}
+
+impl ApiKey {
+    /// Count a request towards the key's daily quota, failing if it has been used up.
+    pub fn consume_quota(&self) -> Result<(), Error> {
+        let Some(quota) = self.daily_quota else {
+            return Ok(());
+        };
+        let today = today();
+        let key = shared::hash_key(("api key", &self.token));
+        let usage = &shared::get().quotas.usage;
+        usage
+            .with(
+                key,
+                |usage| usage.day != today,
+                |usage| {
+                    if usage.day != today {
+                        usage.day = today;
+                        usage.count = 0;
+                    }
+                    if usage.count >= quota {
+                        return Err(Error::TryAgainLater(format!(
+                            "daily quota of {quota} requests used up"
+                        )));
+                    }
+                    usage.count += 1;
+                    Ok(())
+                },
+            )
+            .unwrap_or_else(|| {
+                Err(Error::InternalError(
+                    "no space to track API key usage".to_string(),
+                ))
+            })
+    }
+}
@@ -0,0 +1,166 @@
+//! `ato`, a command-line client for running programs on an Attempt This Online server.
+
+use attempt_this_online::cli::{REQUEST_USAGE, parse_request};
+use attempt_this_online::protocol::{Request, StreamResponse};
+use std::io::Write;
+use std::process::ExitCode;
+use tungstenite::{Message, client::IntoClientRequest};
+
+fn usage() -> String {
+    format!(
+        "\
+usage: ato run --lang LANGUAGE [OPTION...] FILE [-- ARGUMENT...]
+
+Run the program in FILE (or standard input, if FILE is -) on an Attempt This Online server, passing
+it any ARGUMENTs. Its output is written to standard output and standard error as it runs, and ato
+exits with the program's exit status, or 128 plus the number of the signal which killed it.
+
+options:
+{REQUEST_USAGE}
+  --json                instead of writing the output as it comes, write it and the other details
+                        the server sends as a JSON object when the program has finished
+  --url URL             the server's websocket URL (by default $ATO_URL, or the public server)
+
+An API key for the server can be given in $ATO_API_KEY."
+    )
+}
+
+const DEFAULT_URL: &str = "wss://ato.pxeger.com/api/v1/ws/execute";
+
+/// the exit status when ato itself fails, rather than the program
+const FAILURE: u8 = 125;
+const USAGE_ERROR: u8 = 2;
+
+struct Options {
+    url: String,
+    api_key: Option<String>,
+    json: bool,
+    request: Request,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("ato: {e}\n\n{}", usage());
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+    match run(options) {
+        Ok(status) => ExitCode::from(status),
+        Err(e) => {
+            eprintln!("ato: {e}");
+            ExitCode::from(FAILURE)
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        Some("run") => (),
+        Some("-h" | "--help") => {
+            println!("{}", usage());
+            std::process::exit(0);
+        }
+        Some(command) => return Err(format!("unknown command: {command}")),
+        None => return Err("no command given".to_string()),
+    }
+
+    let mut json = false;
+    let mut url = env("ATO_URL").unwrap_or_else(|| DEFAULT_URL.to_string());
+    let request = parse_request(args, |option, value| {
+        match option {
+            "--json" => json = true,
+            "--url" => url = value()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    Ok(Options {
+        url,
+        api_key: env("ATO_API_KEY"),
+        json,
+        request,
+    })
+}
+
+/// Run the request, returning the status ato should exit with.
+fn run(options: Options) -> Result<u8, String> {
+    let mut request = options
+        .url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| format!("invalid URL {}: {e}", options.url))?;
+    if let Some(key) = &options.api_key {
+        let header = format!("Bearer {key}")
+            .parse()
+            .map_err(|_| "$ATO_API_KEY is not a valid header value")?;
+        request.headers_mut().insert("Authorization", header);
+    }
+    let (mut socket, _) = tungstenite::connect(request)
+        .map_err(|e| format!("error connecting to {}: {e}", options.url))?;
+    let message = rmp_serde::to_vec_named(&options.request).unwrap();
+    socket
+        .write_message(Message::Binary(message))
+        .map_err(|e| format!("error sending request: {e}"))?;
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut warnings = vec![];
+    let done = loop {
+        let message = match socket.read_message() {
+            Ok(Message::Binary(message)) => message,
+            Ok(Message::Close(Some(frame))) => return Err(frame.reason.into_owned()),
+            Ok(Message::Close(None)) => return Err("the server closed the connection".to_string()),
+            Ok(_) => continue,
+            Err(e) => return Err(format!("error receiving from server: {e}")),
+        };
+        let response = rmp_serde::from_slice(&message)
+            .map_err(|e| format!("invalid message from server: {e}"))?;
+        let written = match response {
+            StreamResponse::Stdout(data) if options.json => {
+                stdout.extend(data.into_vec());
+                Ok(())
+            }
+            StreamResponse::Stderr(data) if options.json => {
+                stderr.extend(data.into_vec());
+                Ok(())
+            }
+            StreamResponse::Warning(warning) if options.json => {
+                warnings.push(warning);
+                Ok(())
+            }
+            StreamResponse::Stdout(data) => std::io::stdout()
+                .write_all(&data)
+                .and_then(|()| std::io::stdout().flush()),
+            StreamResponse::Stderr(data) => std::io::stderr().write_all(&data),
+            StreamResponse::Warning(warning) => {
+                writeln!(std::io::stderr(), "ato: warning: {warning}")
+            }
+            StreamResponse::Queued { .. } => Ok(()),
+            done @ StreamResponse::Done { .. } => break done,
+        };
+        written.map_err(|e| format!("error writing output: {e}"))?;
+    };
+    // the server keeps the connection open for another request, so say goodbye
+    if socket.close(None).is_ok() {
+        while socket.read_message().is_ok() {}
+    }
+
+    if options.json {
+        let mut done = serde_json::to_value(&done).unwrap();
+        let result = serde_json::json!({
+            "stdout": String::from_utf8_lossy(&stdout),
+            "stderr": String::from_utf8_lossy(&stderr),
+            "warnings": warnings,
+            "done": done["Done"].take(),
+        });
+        println!("{result}");
+    }
+    Ok(done.exit_status().unwrap_or(FAILURE))
+}
@@ -0,0 +1,239 @@
+//! Building a request from command-line arguments, for `ato run` and the server's own `run`
+//! subcommand.
+
+use crate::protocol::Request;
+use serde_bytes::ByteBuf;
+use std::io::Read;
+
+/// The help text for the arguments `parse_request` understands.
+pub const REQUEST_USAGE: &str =
+    "  --lang LANGUAGE       the ID of the language to run the program with (required)
+  --input FILE          give the contents of FILE (or standard input, if FILE is -) to the program
+                        as its standard input
+  --option OPTION       pass OPTION to the compiler or interpreter; can be given more than once
+  --timeout SECONDS     the maximum time to run the program for
+  --lang-version NAME   run a different version of the language than its default";
+
+fn read_file(path: &str) -> Result<ByteBuf, String> {
+    let mut data = vec![];
+    let result = if path == "-" {
+        std::io::stdin().read_to_end(&mut data).map(|_| ())
+    } else {
+        std::fs::File::open(path).and_then(|mut file| file.read_to_end(&mut data).map(|_| ()))
+    };
+    result.map_err(|e| format!("error reading {path}: {e}"))?;
+    Ok(ByteBuf::from(data))
+}
+
+/// Build a request from arguments like `--lang LANGUAGE [OPTION...] FILE [-- ARGUMENT...]`.
+///
+/// Options other than those in `REQUEST_USAGE` are passed to `other`, along with a function which
+/// takes the option's value from the arguments; it returns whether it recognised the option.
+pub fn parse_request(
+    args: impl IntoIterator<Item = String>,
+    mut other: impl FnMut(&str, &mut dyn FnMut() -> Result<String, String>) -> Result<bool, String>,
+) -> Result<Request, String> {
+    let mut args = args.into_iter();
+    let mut language = None;
+    let mut input_path = None;
+    let mut options = vec![];
+    let mut timeout = None;
+    let mut version = None;
+    let mut code_path = None;
+    let mut arguments = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--lang" => language = Some(value()?),
+            "--input" => input_path = Some(value()?),
+            "--option" => options.push(ByteBuf::from(value()?)),
+            "--timeout" => {
+                let seconds = value()?;
+                let seconds = seconds
+                    .parse()
+                    .map_err(|_| format!("invalid timeout: {seconds}"))?;
+                timeout = Some(seconds);
+            }
+            "--lang-version" => version = Some(value()?),
+            "--" => {
+                arguments.extend(args.by_ref().map(ByteBuf::from));
+                break;
+            }
+            _ if arg.starts_with("--") => {
+                if !other(&arg, &mut value)? {
+                    return Err(format!("unknown option: {arg}"));
+                }
+            }
+            _ if code_path.is_none() => code_path = Some(arg),
+            _ => {
+                return Err(format!(
+                    "unexpected argument: {arg} (put -- before arguments)"
+                ));
+            }
+        }
+    }
+    let language = language.ok_or("--lang is required")?;
+    let code_path = code_path.ok_or("no program file given")?;
+    if code_path == "-" && input_path.as_deref() == Some("-") {
+        return Err("the program and its input can't both be standard input".to_string());
+    }
+    let code = read_file(&code_path)?;
+    let input = match &input_path {
+        Some(path) => read_file(path)?,
+        None => ByteBuf::new(),
+    };
+
+    Ok(Request {
+        language,
+        code,
+        custom_runner: None,
+        input,
+        arguments,
+        options,
+        timeout,
+        memory: None,
+        pids: None,
+        unprivileged_user: None,
+        deterministic: false,
+        version,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// a file which is removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ato-cli-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn parse(args: &[&str]) -> Result<Request, String> {
+        parse_request(args.iter().map(|arg| arg.to_string()), |_, _| Ok(false))
+    }
+
+    #[test]
+    fn full_request() {
+        let code = TempFile::new("full-code", "print(input())");
+        let input = TempFile::new("full-input", "hello");
+        let request = parse(&[
+            "--lang",
+            "python",
+            "--option",
+            "-O",
+            "--input",
+            &input.path(),
+            "--option",
+            "-B",
+            "--timeout",
+            "5",
+            "--lang-version",
+            "3.11",
+            &code.path(),
+            "--",
+            "a",
+            "--lang",
+        ])
+        .unwrap();
+        assert_eq!(request.language, "python");
+        assert_eq!(request.code.as_slice(), b"print(input())");
+        assert_eq!(request.input.as_slice(), b"hello");
+        assert_eq!(request.options, [ByteBuf::from("-O"), ByteBuf::from("-B")]);
+        assert_eq!(request.timeout, Some(5));
+        assert_eq!(request.version.as_deref(), Some("3.11"));
+        // everything after -- is an argument, even if it looks like an option
+        assert_eq!(
+            request.arguments,
+            [ByteBuf::from("a"), ByteBuf::from("--lang")]
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        let code = TempFile::new("defaults-code", "");
+        let request = parse(&[&code.path(), "--lang", "zsh"]).unwrap();
+        assert!(request.input.is_empty());
+        assert!(request.options.is_empty() && request.arguments.is_empty());
+        assert_eq!((request.timeout, request.version), (None, None));
+    }
+
+    #[test]
+    fn errors() {
+        let code = TempFile::new("errors-code", "");
+        let code = code.path();
+        assert_eq!(parse(&[&code]).unwrap_err(), "--lang is required");
+        assert_eq!(
+            parse(&["--lang", "zsh"]).unwrap_err(),
+            "no program file given"
+        );
+        assert_eq!(
+            parse(&[&code, "--lang"]).unwrap_err(),
+            "--lang needs a value"
+        );
+        assert_eq!(
+            parse(&["--lang", "zsh", &code, "--timeout", "soon"]).unwrap_err(),
+            "invalid timeout: soon"
+        );
+        assert_eq!(
+            parse(&["--lang", "zsh", "--memory", "1", &code]).unwrap_err(),
+            "unknown option: --memory"
+        );
+        assert!(
+            parse(&["--lang", "zsh", &code, "extra"])
+                .unwrap_err()
+                .starts_with("unexpected argument: extra")
+        );
+        assert_eq!(
+            parse(&["--lang", "zsh", "--input", "-", "-"]).unwrap_err(),
+            "the program and its input can't both be standard input"
+        );
+        let missing = format!("{code}-missing");
+        assert!(
+            parse(&["--lang", "zsh", &missing])
+                .unwrap_err()
+                .starts_with(&format!("error reading {missing}: "))
+        );
+    }
+
+    #[test]
+    fn other_options() {
+        let code = TempFile::new("other-code", "");
+        let mut seen = vec![];
+        let args = [
+            "--json",
+            "--lang",
+            "zsh",
+            "--server",
+            "example",
+            &code.path(),
+        ];
+        let request = parse_request(args.iter().map(|arg| arg.to_string()), |option, value| {
+            match option {
+                "--json" => seen.push(option.to_string()),
+                "--server" => seen.push(value()?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(request.language, "zsh");
+        assert_eq!(seen, ["--json", "example"]);
+    }
+}
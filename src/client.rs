@@ -0,0 +1,186 @@
+use crate::auth::{self, ApiKey};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::LazyLock;
+use tungstenite::handshake::server as http;
+
+/// Information about who is making requests on a connection
+#[derive(Debug)]
+pub struct Client {
+    pub address: IpAddr,
+    pub api_key: Option<&'static ApiKey>,
+}
+
+fn get_trusted_proxies() -> Vec<IpAddr> {
+    let proxies = std::env::var("ATO_TRUSTED_PROXIES").unwrap_or_else(|e| {
+        if let std::env::VarError::NotUnicode(_) = e {
+            panic!("$ATO_TRUSTED_PROXIES is invalid Unicode")
+        }
+        // nginx runs on the same machine in the default setup
+        "127.0.0.1,::1".to_string()
+    });
+    proxies
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            p.parse()
+                .unwrap_or_else(|_| panic!("$ATO_TRUSTED_PROXIES contains an invalid address: {p}"))
+        })
+        .collect()
+}
+
+/// addresses of reverse proxies whose forwarding headers we believe
+pub static TRUSTED_PROXIES: LazyLock<Vec<IpAddr>> = LazyLock::new(get_trusted_proxies);
+
+fn is_trusted(address: &IpAddr) -> bool {
+    TRUSTED_PROXIES.contains(address)
+}
+
+fn parse_address(address: &str) -> Option<IpAddr> {
+    address
+        .trim()
+        .parse::<IpAddr>()
+        .ok()
+        .map(|a| a.to_canonical())
+}
+
+fn get_address(peer: IpAddr, request: &http::Request) -> IpAddr {
+    let peer = peer.to_canonical();
+    if !is_trusted(&peer) {
+        // anyone can send these headers, so only believe them if they come from our own proxy
+        return peer;
+    }
+    let headers = request.headers();
+
+    // nginx replaces X-Real-IP with the address it received the connection from, so prefer that
+    if let Some(address) = headers
+        .get("X-Real-IP")
+        .and_then(|h| h.to_str().ok())
+        .and_then(parse_address)
+        && !is_trusted(&address)
+    {
+        return address;
+    }
+
+    // otherwise, each proxy appends the address it received the connection from to X-Forwarded-For,
+    // so the client is the last address in the list which wasn't added by one of our proxies
+    let forwarded_for: Vec<IpAddr> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(parse_address)
+        .collect();
+    forwarded_for
+        .into_iter()
+        .rev()
+        .find(|a| !is_trusted(a))
+        .unwrap_or(peer)
+}
+
+impl Client {
+    /// Identify the client making a websocket handshake request, which was received from `peer`.
+    /// Returns None if the request has an invalid API key.
+    pub fn from_request(peer: IpAddr, request: &http::Request) -> Option<Self> {
+        let api_key = match request.headers().get("Authorization") {
+            None => None,
+            Some(header) => Some(auth::authenticate(header.to_str().ok()?)?),
+        };
+        Some(Client {
+            address: get_address(peer, request),
+            api_key,
+        })
+    }
+
+    /// The server itself, for requests it runs on its own behalf, which have the same limits as
+    /// anonymous clients.
+    pub fn local() -> Self {
+        Client {
+            address: IpAddr::V6(Ipv6Addr::LOCALHOST),
+            api_key: None,
+        }
+    }
+
+    /// the permissions and limits which apply to this client's requests
+    pub fn limits(&self) -> &'static ApiKey {
+        self.api_key.unwrap_or(&auth::ANONYMOUS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // these rely on $ATO_TRUSTED_PROXIES not being set, so only 127.0.0.1 and ::1 are trusted
+
+    fn address(peer: &str, headers: &[(&str, &str)]) -> IpAddr {
+        let mut request = tungstenite::http::Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        get_address(peer.parse().unwrap(), &request.body(()).unwrap())
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn headers_ignored_from_untrusted_peer() {
+        let headers = [("X-Forwarded-For", "1.1.1.1"), ("X-Real-IP", "2.2.2.2")];
+        assert_eq!(address("9.9.9.9", &headers), ip("9.9.9.9"));
+    }
+
+    #[test]
+    fn real_ip_preferred() {
+        let headers = [("X-Forwarded-For", "1.1.1.1"), ("X-Real-IP", "2.2.2.2")];
+        assert_eq!(address("127.0.0.1", &headers), ip("2.2.2.2"));
+    }
+
+    #[test]
+    fn spoofed_forwarded_for() {
+        // the client sent its own X-Forwarded-For, which nginx appended the real address to
+        let headers = [("X-Forwarded-For", "1.1.1.1, 6.6.6.6")];
+        assert_eq!(address("127.0.0.1", &headers), ip("6.6.6.6"));
+        // one which claims to come from a trusted proxy is no better
+        let headers = [("X-Forwarded-For", "1.1.1.1, 127.0.0.1, 6.6.6.6")];
+        assert_eq!(address("::1", &headers), ip("6.6.6.6"));
+        // nor are invalid entries, which are skipped
+        let headers = [("X-Forwarded-For", "6.6.6.6, not an address")];
+        assert_eq!(address("127.0.0.1", &headers), ip("6.6.6.6"));
+    }
+
+    #[test]
+    fn chain_of_trusted_proxies() {
+        let headers = [("X-Forwarded-For", "1.1.1.1, 6.6.6.6, 127.0.0.1")];
+        assert_eq!(address("127.0.0.1", &headers), ip("6.6.6.6"));
+        // across several headers too
+        let headers = [
+            ("X-Forwarded-For", "1.1.1.1, 6.6.6.6"),
+            ("X-Forwarded-For", "::1"),
+        ];
+        assert_eq!(address("127.0.0.1", &headers), ip("6.6.6.6"));
+    }
+
+    #[test]
+    fn trusted_or_invalid_real_ip_ignored() {
+        let headers = [("X-Real-IP", "127.0.0.1"), ("X-Forwarded-For", "6.6.6.6")];
+        assert_eq!(address("127.0.0.1", &headers), ip("6.6.6.6"));
+        let headers = [("X-Real-IP", "nonsense"), ("X-Forwarded-For", "6.6.6.6")];
+        assert_eq!(address("127.0.0.1", &headers), ip("6.6.6.6"));
+    }
+
+    #[test]
+    fn falls_back_to_peer() {
+        assert_eq!(address("127.0.0.1", &[]), ip("127.0.0.1"));
+        let headers = [("X-Forwarded-For", "::1, 127.0.0.1")];
+        assert_eq!(address("127.0.0.1", &headers), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        // an IPv4 proxy connecting over IPv6 is still trusted, and addresses are compared as IPv4
+        let headers = [("X-Forwarded-For", "::ffff:6.6.6.6")];
+        assert_eq!(address("::ffff:127.0.0.1", &headers), ip("6.6.6.6"));
+    }
+}
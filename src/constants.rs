@@ -5,3 +5,12 @@ pub const KiB: u64 = 1024;
 #[allow(non_upper_case_globals)]
 pub const MiB: u64 = KiB * KiB;
 pub const MAX_REQUEST_SIZE: usize = 64 * KiB as usize;
+/// the highest timeout requests can ask for, unless the language or API key says otherwise, in
+/// seconds
+pub const DEFAULT_MAX_TIMEOUT: i32 = 60;
+/// the highest memory limit requests can ask for, unless the language or API key says otherwise, in
+/// MiB
+pub const DEFAULT_MAX_MEMORY: u64 = 1024;
+/// the highest number of processes and threads requests can ask for, unless the language or API key
+/// says otherwise
+pub const DEFAULT_MAX_PIDS: u64 = 100;
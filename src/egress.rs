@@ -0,0 +1,616 @@
+//! Outbound network access for sandboxes whose language or API key allows it.
+//!
+//! Sandboxes never get a route out of their own network namespace. Instead, the sandbox listens on
+//! `127.0.0.1:3128` inside its namespace, and passes the listening socket back out (through the
+//! zygote) to the worker thread running the request, which serves it as an HTTP proxy. The proxy
+//! only connects to the hosts and ports on the policy's allow-list, limits how many connections can
+//! be open at once, and limits the bandwidth used by all of them together. The runner's environment
+//! has `http_proxy` and `https_proxy` pointing at the proxy, which most HTTP clients understand.
+//!
+//! Both `CONNECT` requests (for HTTPS and other protocols) and plain HTTP requests with an absolute
+//! URL are supported. Plain HTTP requests are passed on with `Connection: close`, so each
+//! connection to the proxy is only ever used for one request to one host.
+
+use crate::{Error, check};
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, poll},
+    sys::socket::{
+        self, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+        SockaddrStorage, recvmsg, sendmsg, sockopt,
+    },
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The limits on a sandbox's outbound connections, given by its language or API key.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// `host:port` pairs which can be connected to, where the port can be `*` to allow any port.
+    /// IPv6 addresses are written in square brackets
+    pub allow: Vec<String>,
+    /// maximum number of connections which can be open at once
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// maximum number of bytes per second, in both directions and across all connections, or
+    /// unlimited if not given
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+fn default_max_connections() -> usize {
+    4
+}
+
+impl Policy {
+    fn allows(&self, host: &str, port: u16) -> bool {
+        self.allow.iter().any(|entry| {
+            let Some((allowed_host, allowed_port)) = split_host_port(entry) else {
+                return false;
+            };
+            allowed_host.eq_ignore_ascii_case(host)
+                && (allowed_port == "*" || allowed_port.parse() == Ok(port))
+        })
+    }
+}
+
+/// the address the proxy listens on inside the sandbox
+const PROXY_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::LOCALHOST, 3128);
+
+/// environment variables for sandboxes with a proxy, which replace any the image sets
+pub const PROXY_ENV: &[(&str, &str)] = &[
+    ("http_proxy", "http://127.0.0.1:3128"),
+    ("https_proxy", "http://127.0.0.1:3128"),
+    ("HTTP_PROXY", "http://127.0.0.1:3128"),
+    ("HTTPS_PROXY", "http://127.0.0.1:3128"),
+    ("no_proxy", "localhost,127.0.0.1,::1"),
+    ("NO_PROXY", "localhost,127.0.0.1,::1"),
+];
+
+/// maximum size of the request line and headers of a request to the proxy
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const BUF_SIZE: usize = 16 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Start listening for the proxy inside the sandbox, and send the listening socket to the worker.
+/// Must be called after the loopback interface has been brought up.
+pub fn listen(socket: RawFd) -> Result<(), Error> {
+    let listener = check!(
+        TcpListener::bind(PROXY_ADDRESS),
+        "error listening for egress proxy: {}"
+    );
+    check!(
+        sendmsg::<()>(
+            socket,
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(&[listener.as_raw_fd()])],
+            MsgFlags::empty(),
+            None
+        ),
+        "error sending egress proxy listener: {}"
+    );
+    Ok(())
+}
+
+/// Serve the sandbox's proxy until the quit eventfd is triggered.
+pub fn serve(policy: &Policy, socket: RawFd, quit: RawFd) -> Result<(), Error> {
+    let Some(listener) = receive_listener(socket, quit)? else {
+        // the sandbox is gone, or failed before it got as far as listening
+        return Ok(());
+    };
+    let listener = TcpListener::from(listener);
+    check!(
+        listener.set_nonblocking(true),
+        "error setting O_NONBLOCK on egress proxy listener: {}"
+    );
+    let proxy = Proxy {
+        policy,
+        quit,
+        connections: Mutex::new(Some(HashMap::new())),
+        throttle: Throttle {
+            bandwidth: policy.bandwidth,
+            paid_until: Mutex::new(Instant::now()),
+        },
+    };
+    std::thread::scope(|threads| {
+        let result = (|| {
+            for id in 0.. {
+                if !wait_readable(listener.as_raw_fd(), quit)? {
+                    return Ok(());
+                }
+                let mut client = match listener.accept() {
+                    Ok((client, _)) => client,
+                    // the client gave up on the connection already
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) if e.kind() == ErrorKind::ConnectionAborted => continue,
+                    Err(e) => {
+                        return Err(Error::InternalError(format!(
+                            "error accepting egress proxy connection: {e}"
+                        )));
+                    }
+                };
+                // checked before starting a thread for it, so that connections over the limit can't
+                // use up threads either
+                if !proxy.register(id, &client, policy.max_connections) {
+                    let _ = client.set_nonblocking(true);
+                    let _ = respond(
+                        &mut client,
+                        "503 Service Unavailable",
+                        "too many connections",
+                    );
+                    continue;
+                }
+                let proxy = &proxy;
+                threads.spawn(move || proxy.handle(id, client));
+            }
+            Ok(())
+        })();
+        proxy.stop();
+        result
+    })
+}
+
+/// wait for a message on the socket, and take the listening socket attached to it
+fn receive_listener(socket: RawFd, quit: RawFd) -> Result<Option<OwnedFd>, Error> {
+    if !wait_readable(socket, quit)? {
+        return Ok(None);
+    }
+    let mut buf = [0];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_space = nix::cmsg_space!(RawFd);
+    let message = check!(
+        recvmsg::<()>(
+            socket,
+            &mut iov,
+            Some(&mut cmsg_space),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ),
+        "error receiving egress proxy listener: {}"
+    );
+    Ok(message.cmsgs().find_map(|cmsg| match cmsg {
+        // this is safe because the fd was just received, so nothing else owns it
+        ControlMessageOwned::ScmRights(fds) => {
+            fds.first().map(|&fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        }
+        _ => None,
+    }))
+}
+
+/// Wait until the fd is readable (returning true), or the quit eventfd is triggered (returning
+/// false).
+fn wait_readable(fd: RawFd, quit: RawFd) -> Result<bool, Error> {
+    let mut poll_args = [
+        PollFd::new(quit, PollFlags::POLLIN),
+        PollFd::new(fd, PollFlags::POLLIN),
+    ];
+    loop {
+        match poll(&mut poll_args, -1 /* infinite timeout */) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            result => check!(result, "error polling egress proxy: {}"),
+        };
+        let [poll_quit, poll_fd] = poll_args;
+        if poll_quit.revents() != Some(PollFlags::empty()) {
+            return Ok(false);
+        }
+        if poll_fd.revents() != Some(PollFlags::empty()) {
+            return Ok(true);
+        }
+    }
+}
+
+struct Proxy<'a> {
+    policy: &'a Policy,
+    /// the eventfd which is triggered when the sandbox is stopped
+    quit: RawFd,
+    /// copies of the sockets of each open connection, so that they can all be shut down when the
+    /// sandbox is stopped. None once that has happened
+    connections: Mutex<Option<HashMap<u64, Vec<TcpStream>>>>,
+    throttle: Throttle,
+}
+
+impl Proxy<'_> {
+    /// Serve a connection which has already been registered.
+    fn handle(&self, id: u64, mut client: TcpStream) {
+        let _ = self.proxy(id, &mut client);
+        self.unregister(id);
+    }
+
+    /// Keep a copy of the socket for stop. Returns false if the connection shouldn't go ahead.
+    fn register(&self, id: u64, stream: &TcpStream, max_connections: usize) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(connections) = connections.as_mut() else {
+            return false;
+        };
+        let Ok(stream) = stream.try_clone() else {
+            return false;
+        };
+        let streams = connections.entry(id).or_default();
+        if streams.is_empty() && connections.len() > max_connections {
+            connections.remove(&id);
+            return false;
+        }
+        connections.get_mut(&id).unwrap().push(stream);
+        true
+    }
+
+    fn unregister(&self, id: u64) {
+        if let Some(connections) = self.connections.lock().unwrap().as_mut() {
+            for stream in connections.remove(&id).unwrap_or_default() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// shut down every open connection, so that the threads handling them finish
+    fn stop(&self) {
+        let connections = self.connections.lock().unwrap().take().unwrap_or_default();
+        for stream in connections.into_values().flatten() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn proxy(&self, id: u64, client: &mut TcpStream) -> std::io::Result<()> {
+        let Some((head, rest)) = read_head(client)? else {
+            return respond(client, "400 Bad Request", "request too large or incomplete");
+        };
+        let Some(request) = parse_request(&head) else {
+            return respond(client, "400 Bad Request", "invalid proxy request");
+        };
+        if !self.policy.allows(&request.host, request.port) {
+            return respond(
+                client,
+                "403 Forbidden",
+                &format!("not allowed: {}:{}", request.host, request.port),
+            );
+        }
+        let Some(mut server) = connect(&request.host, request.port, self.quit) else {
+            return respond(
+                client,
+                "502 Bad Gateway",
+                &format!("error connecting to {}:{}", request.host, request.port),
+            );
+        };
+        if !self.register(id, &server, usize::MAX) {
+            return Ok(());
+        }
+        match request.forward {
+            // CONNECT
+            None => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+            Some(head) => server.write_all(&head)?,
+        }
+        server.write_all(&rest)?;
+        std::thread::scope(|threads| {
+            let (mut client2, mut server2) = (client.try_clone()?, server.try_clone()?);
+            let upload = threads.spawn(move || self.relay(&mut client2, &mut server2));
+            let download = self.relay(&mut server, client);
+            let _ = upload.join();
+            download
+        })
+    }
+
+    /// Copy everything from one socket to the other, until the first one is closed.
+    fn relay(&self, from: &mut TcpStream, to: &mut TcpStream) -> std::io::Result<()> {
+        let mut buf = [0; BUF_SIZE];
+        // read at most about a second's worth at a time, so that stopping isn't delayed for long
+        let max_read = self.throttle.bandwidth.map_or(BUF_SIZE, |bandwidth| {
+            BUF_SIZE.min(bandwidth.max(1) as usize)
+        });
+        let result = (|| {
+            loop {
+                let len = from.read(&mut buf[..max_read])?;
+                if len == 0 {
+                    return Ok(());
+                }
+                self.throttle.take(len);
+                to.write_all(&buf[..len])?;
+            }
+        })();
+        // pass on the end of the stream, or give up entirely if something went wrong
+        let _ = to.shutdown(if result.is_ok() {
+            Shutdown::Write
+        } else {
+            Shutdown::Both
+        });
+        result
+    }
+}
+
+/// Read the request line and headers, returning them and anything read after them.
+fn read_head(client: &mut TcpStream) -> std::io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut data = vec![];
+    let mut buf = [0; BUF_SIZE];
+    loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = data.split_off(end + 4);
+            return Ok(Some((data, rest)));
+        }
+        if data.len() > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+        let len = client.read(&mut buf)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        data.extend(&buf[..len]);
+    }
+}
+
+struct ProxyRequest {
+    host: String,
+    port: u16,
+    /// for plain HTTP requests, the request line and headers to send on, or None for CONNECT
+    forward: Option<Vec<u8>>,
+}
+
+fn parse_request(head: &[u8]) -> Option<ProxyRequest> {
+    let head = std::str::from_utf8(head).ok()?;
+    let (line, headers) = head.split_once("\r\n")?;
+    let mut parts = line.split(' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || !version.starts_with("HTTP/") {
+        return None;
+    }
+    if method == "CONNECT" {
+        let (host, port) = split_host_port(target)?;
+        return Some(ProxyRequest {
+            host: host.to_string(),
+            port: port.parse().ok()?,
+            forward: None,
+        });
+    }
+    let scheme_length = "http://".len();
+    if !target.get(..scheme_length)?.eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let target = &target[scheme_length..];
+    let (authority, path) = match target.find(['/', '?']) {
+        Some(i) if target[i..].starts_with('?') => (&target[..i], format!("/{}", &target[i..])),
+        Some(i) => (&target[..i], target[i..].to_string()),
+        None => (target, "/".to_string()),
+    };
+    if authority.contains('@') {
+        return None;
+    }
+    let (host, port) = match split_host_port(authority) {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority.trim_start_matches('[').trim_end_matches(']'), 80),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let mut forward = format!("{method} {path} {version}\r\n");
+    for header in headers.split("\r\n").filter(|header| !header.is_empty()) {
+        let name = header.split(':').next().unwrap_or_default().trim();
+        if !["connection", "proxy-connection", "proxy-authorization"]
+            .iter()
+            .any(|hop_by_hop| name.eq_ignore_ascii_case(hop_by_hop))
+        {
+            forward += header;
+            forward += "\r\n";
+        }
+    }
+    forward += "Connection: close\r\n\r\n";
+    Some(ProxyRequest {
+        host: host.to_string(),
+        port,
+        forward: Some(forward.into_bytes()),
+    })
+}
+
+/// split `host:port` or `[address]:port` into its host and port
+fn split_host_port(s: &str) -> Option<(&str, &str)> {
+    if let Some(s) = s.strip_prefix('[') {
+        let (host, port) = s.split_once("]:")?;
+        Some((host, port))
+    } else {
+        // more colons means an IPv6 address without brackets
+        s.split_once(':').filter(|(_, port)| !port.contains(':'))
+    }
+}
+
+/// Connect to the first of the host's addresses which works, unless the quit eventfd is triggered
+/// first. Looking up the host can't be interrupted, but the resolver has its own timeout.
+fn connect(host: &str, port: u16, quit: RawFd) -> Option<TcpStream> {
+    for address in (host, port).to_socket_addrs().ok()? {
+        match connect_address(address, quit) {
+            Ok(Some(stream)) => return Some(stream),
+            // the sandbox has been stopped
+            Ok(None) => return None,
+            Err(_) => continue,
+        }
+    }
+    None
+}
+
+/// Connect to the address, waiting at most CONNECT_TIMEOUT. Returns None if the quit eventfd is
+/// triggered first.
+fn connect_address(address: SocketAddr, quit: RawFd) -> std::io::Result<Option<TcpStream>> {
+    let family = match address {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket::socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    // this is safe because the fd was just created, so nothing else owns it
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    match socket::connect(fd, &SockaddrStorage::from(address)) {
+        Ok(()) | Err(Errno::EINPROGRESS) => (),
+        Err(e) => return Err(e.into()),
+    }
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let mut poll_args = [
+        PollFd::new(quit, PollFlags::POLLIN),
+        PollFd::new(fd, PollFlags::POLLOUT),
+    ];
+    loop {
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis() as i32;
+        match poll(&mut poll_args, timeout) {
+            Err(Errno::EINTR) => continue,
+            Ok(0) => return Err(Errno::ETIMEDOUT.into()),
+            result => result?,
+        };
+        let [poll_quit, poll_fd] = poll_args;
+        if poll_quit.revents() != Some(PollFlags::empty()) {
+            return Ok(None);
+        }
+        if poll_fd.revents() != Some(PollFlags::empty()) {
+            break;
+        }
+    }
+    match socket::getsockopt(fd, sockopt::SocketError)? {
+        0 => (),
+        e => return Err(std::io::Error::from_raw_os_error(e)),
+    }
+    stream.set_nonblocking(false)?;
+    Ok(Some(stream))
+}
+
+fn respond(client: &mut TcpStream, status: &str, message: &str) -> std::io::Result<()> {
+    let body = format!("ATO egress proxy: {message}\n");
+    write!(
+        client,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Limits the bandwidth shared between all of a sandbox's connections.
+struct Throttle {
+    /// in bytes per second
+    bandwidth: Option<u64>,
+    /// when all the data sent so far would have been sent, at exactly the bandwidth limit
+    paid_until: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// allow bursts of up to this long's worth of data
+    const BURST: Duration = Duration::from_secs(1);
+
+    /// Wait until sending some more data would keep within the bandwidth limit.
+    fn take(&self, len: usize) {
+        let Some(bandwidth) = self.bandwidth else {
+            return;
+        };
+        let now = Instant::now();
+        let wait = {
+            let mut paid_until = self.paid_until.lock().unwrap();
+            *paid_until = (*paid_until).max(now)
+                + Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+            paid_until.saturating_duration_since(now + Self::BURST)
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Option<(String, u16, Option<String>)> {
+        let request = parse_request(head.as_bytes())?;
+        let forward = request.forward.map(|f| String::from_utf8(f).unwrap());
+        Some((request.host, request.port, forward))
+    }
+
+    #[test]
+    fn connect() {
+        let parsed = parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+        assert_eq!(parsed, Some(("example.com".into(), 443, None)));
+        let parsed = parse("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n");
+        assert_eq!(parsed, Some(("2001:db8::1".into(), 443, None)));
+        // the port is required
+        assert_eq!(parse("CONNECT example.com HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse("CONNECT 2001:db8::1:443 HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse("CONNECT example.com:https HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn absolute_urls() {
+        let head = "GET http://example.com/a/b?c HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let forward = "GET /a/b?c HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+        assert_eq!(
+            parse(head),
+            Some(("example.com".into(), 80, Some(forward.into())))
+        );
+        let parsed = parse("POST HTTP://Example.com:8080 HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!((parsed.0.as_str(), parsed.1), ("Example.com", 8080));
+        assert!(parsed.2.unwrap().starts_with("POST / HTTP/1.0\r\n"));
+        let parsed = parse("GET http://example.com?q HTTP/1.1\r\n\r\n").unwrap();
+        assert!(parsed.2.unwrap().starts_with("GET /?q HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn ipv6_urls() {
+        let parsed = parse("GET http://[::1]:8080/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((parsed.0.as_str(), parsed.1), ("::1", 8080));
+        let parsed = parse("GET http://[2001:db8::1]/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((parsed.0.as_str(), parsed.1), ("2001:db8::1", 80));
+    }
+
+    #[test]
+    fn hop_by_hop_headers_removed() {
+        let head = "GET http://example.com/ HTTP/1.1\r\nProxy-Authorization: x\r\n\
+            connection: keep-alive\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n";
+        let forward = "GET / HTTP/1.1\r\nAccept: */*\r\nConnection: close\r\n\r\n";
+        assert_eq!(parse(head).unwrap().2.unwrap(), forward);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        // origin-form, as if it weren't going through a proxy
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), None);
+        assert_eq!(parse("GET https://example.com/ HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse("GET http://user@example.com/ HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse("GET http:///path HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(
+            parse("GET http://example.com:99999/ HTTP/1.1\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            parse("GET http://example.com/ HTTP/1.1 extra\r\n\r\n"),
+            None
+        );
+        assert_eq!(parse("GET http://example.com/ FTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse("GET http://example.com/"), None);
+    }
+
+    #[test]
+    fn host_and_port() {
+        assert_eq!(
+            split_host_port("example.com:80"),
+            Some(("example.com", "80"))
+        );
+        assert_eq!(split_host_port("example.com:*"), Some(("example.com", "*")));
+        assert_eq!(split_host_port("[::1]:443"), Some(("::1", "443")));
+        assert_eq!(split_host_port("example.com"), None);
+        assert_eq!(split_host_port("::1:443"), None);
+        assert_eq!(split_host_port("[::1]"), None);
+        assert_eq!(split_host_port("[::1]443"), None);
+    }
+
+    #[test]
+    fn policy() {
+        let policy = Policy {
+            allow: vec!["Example.com:443".into(), "[::1]:*".into(), "invalid".into()],
+            max_connections: 4,
+            bandwidth: None,
+        };
+        assert!(policy.allows("example.COM", 443));
+        assert!(!policy.allows("example.com", 80));
+        assert!(!policy.allows("sub.example.com", 443));
+        assert!(policy.allows("::1", 8080));
+        assert!(!policy.allows("invalid", 80));
+    }
+}
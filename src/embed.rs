@@ -0,0 +1,152 @@
+//! Running programs in the sandbox from other Rust programs, without the websocket server.
+//!
+//! Call [`init`] once, at the start of `main`. Then, for each program, configure a [`Sandbox`] with
+//! a [`Builder`], and [`run`](Sandbox::run) it with an [`OutputSink`] for its output and the Done
+//! message, and a [`ControlSource`] for control messages (or [`NoControl`]). Each run blocks its
+//! thread until the program has finished, so several can be run at once from different threads.
+//!
+//! The sandbox is configured by the same environment variables as the server (`$ATO_LANGUAGES`,
+//! `$ATO_CGROUP_PATH`, `$ATO_SANDBOX_IDS` and so on; see docs/installation.md), and needs the same
+//! privileges.
+
+use crate::{Limits, Request, client::Client, ids, languages, resolve_language, validate};
+use crate::{sandbox, versions, zygote};
+use serde_bytes::ByteBuf;
+use std::sync::LazyLock;
+
+pub use crate::sandbox::{ControlSource, NoControl, OutputSink};
+pub use crate::{ControlMessage, Error, StreamResponse};
+
+/// Read the configuration and start the process which creates the sandboxes.
+///
+/// This must be called before the program starts any threads or opens anything the sandboxes
+/// shouldn't inherit. Afterwards, this process gives up the privileges it no longer needs, in the
+/// same way as the server (see "Privilege separation" in docs/installation.md).
+pub fn init() {
+    LazyLock::force(&ids::RANGE);
+    languages::load();
+    versions::load();
+    zygote::start();
+}
+
+/// The configuration of a program to run, which is the same as a request from a client (see
+/// docs/api.md).
+pub struct Builder {
+    request: Request,
+}
+
+impl Builder {
+    /// a program in the language with the given ID, with no input, arguments or options
+    pub fn new(language: impl Into<String>, code: impl Into<Vec<u8>>) -> Self {
+        Self::from(Request {
+            language: language.into(),
+            code: ByteBuf::from(code),
+            custom_runner: None,
+            input: ByteBuf::new(),
+            arguments: vec![],
+            options: vec![],
+            timeout: None,
+            memory: None,
+            pids: None,
+            unprivileged_user: None,
+            deterministic: false,
+            version: None,
+        })
+    }
+
+    pub fn input(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.request.input = ByteBuf::from(input);
+        self
+    }
+
+    /// add an argument for the program itself
+    pub fn argument(mut self, argument: impl Into<Vec<u8>>) -> Self {
+        self.request.arguments.push(ByteBuf::from(argument));
+        self
+    }
+
+    /// add an argument for the compiler or interpreter
+    pub fn option(mut self, option: impl Into<Vec<u8>>) -> Self {
+        self.request.options.push(ByteBuf::from(option));
+        self
+    }
+
+    /// a Bash script to run instead of the language's runner
+    pub fn custom_runner(mut self, script: impl Into<Vec<u8>>) -> Self {
+        self.request.custom_runner = Some(ByteBuf::from(script));
+        self
+    }
+
+    /// in seconds (by default, the language's default)
+    pub fn timeout(mut self, timeout: i32) -> Self {
+        self.request.timeout = Some(timeout);
+        self
+    }
+
+    /// in MiB (by default, the language's default)
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.request.memory = Some(memory);
+        self
+    }
+
+    /// which of the language's versions to run (by default, the language's default)
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.request.version = Some(version.into());
+        self
+    }
+
+    /// whether the runner can switch to an unprivileged user (by default, the language decides)
+    pub fn unprivileged_user(mut self, unprivileged_user: bool) -> Self {
+        self.request.unprivileged_user = Some(unprivileged_user);
+        self
+    }
+
+    /// whether to hide the time and other sources of variation from the program, as far as possible
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.request.deterministic = deterministic;
+        self
+    }
+
+    /// Check the configuration, in the same way as a request from a client without an API key.
+    /// Errors are `PolicyViolation`s, with the same messages a client would get.
+    pub fn build(mut self) -> Result<Sandbox, Error> {
+        let warning = resolve_language(&mut self.request);
+        let (_, limits) = validate(&self.request, &Client::local())?;
+        Ok(Sandbox {
+            request: self.request,
+            limits,
+            warning,
+        })
+    }
+}
+
+impl From<Request> for Builder {
+    fn from(request: Request) -> Self {
+        Self { request }
+    }
+}
+
+/// A program which is ready to run.
+pub struct Sandbox {
+    request: Request,
+    limits: Limits,
+    warning: Option<String>,
+}
+
+impl Sandbox {
+    /// something which should be changed about the configuration, but which doesn't stop it from
+    /// running, like the Warning message a client would get
+    pub fn warning(&self) -> Option<&str> {
+        self.warning.as_deref()
+    }
+
+    /// Run the program, sending its output and then a Done message to `output`. This returns once
+    /// the program has finished, and can be called more than once.
+    pub fn run(
+        &self,
+        output: &mut dyn OutputSink,
+        control: &mut dyn ControlSource,
+    ) -> Result<(), Error> {
+        sandbox::invoke(&self.request, &self.limits, output, control)
+    }
+}
@@ -0,0 +1,248 @@
+//! Allocation of the user and group IDs which sandboxes run as, from the point of view of the rest
+//! of the system.
+//!
+//! If `$ATO_SANDBOX_IDS` is set (as `FIRST:COUNT`, like a line of /etc/subuid), each running sandbox
+//! gets its own pair of IDs from that range, each used as both a user and group ID, so that
+//! sandboxes can't signal each other or the server, and per-user limits apply to each one
+//! separately. The first is for root inside the container, and the second is for the unprivileged
+//! user which the runner can switch to. Otherwise, every sandbox runs as the server's own user, and
+//! there is no unprivileged user.
+//!
+//! The self-test uses `$ATO_SELF_TEST_IDS` instead, if it is set, so that it can run alongside the
+//! server without their sandboxes sharing IDs.
+//!
+//! Which IDs are in use is recorded in shared memory, which only the zygote has, so that a new
+//! zygote started by an upgrade doesn't reuse the IDs of the old one's sandboxes which are still
+//! running.
+
+use crate::{Error, lifecycle};
+use nix::{
+    sys::{
+        memfd::{MemFdCreateFlag, memfd_create},
+        mman::{MapFlags, ProtFlags, mmap},
+        stat::fstat,
+    },
+    unistd::{Gid, Uid, ftruncate},
+};
+use std::num::NonZeroUsize;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{LazyLock, OnceLock};
+
+pub static RANGE: LazyLock<Option<(u32, u32)>> = LazyLock::new(|| {
+    let server = read_range("ATO_SANDBOX_IDS");
+    if !SELF_TEST.load(SeqCst) {
+        return server;
+    }
+    self_test_range(server, read_range("ATO_SELF_TEST_IDS"))
+});
+
+// whether this process is running the self-test, so RANGE should come from $ATO_SELF_TEST_IDS
+static SELF_TEST: AtomicBool = AtomicBool::new(false);
+
+/// Use the self-test's range of IDs instead of the server's. Must be called before RANGE is used.
+pub fn use_self_test_range() {
+    SELF_TEST.store(true, SeqCst);
+}
+
+/// the self-test's own range if it has one, which must be separate from the server's
+fn self_test_range(server: Option<(u32, u32)>, own: Option<(u32, u32)>) -> Option<(u32, u32)> {
+    let Some(own) = own else {
+        return server;
+    };
+    if let Some((first, count)) = server
+        && own.0 < first + count
+        && first < own.0 + own.1
+    {
+        panic!("$ATO_SELF_TEST_IDS overlaps $ATO_SANDBOX_IDS");
+    }
+    Some(own)
+}
+
+fn read_range(name: &str) -> Option<(u32, u32)> {
+    match std::env::var(name) {
+        Ok(value) => Some(parse_range(name, &value)),
+        Err(std::env::VarError::NotPresent) => None,
+        Err(std::env::VarError::NotUnicode(_)) => panic!("${name} is invalid Unicode"),
+    }
+}
+
+fn parse_range(name: &str, value: &str) -> (u32, u32) {
+    let (first, count) = value
+        .split_once(':')
+        .and_then(|(first, count)| Some((first.parse().ok()?, count.parse().ok()?)))
+        .unwrap_or_else(|| panic!("${name} must be of the form FIRST:COUNT"));
+    if first == 0 || count < IDS_PER_SANDBOX || u32::checked_add(first, count).is_none() {
+        panic!("${name} is not a valid range of IDs");
+    }
+    (first, count)
+}
+
+/// How many sandboxes can have their own IDs at once, if they do.
+pub fn max_sandboxes() -> Option<usize> {
+    RANGE.map(|(_, count)| (count / IDS_PER_SANDBOX) as usize)
+}
+
+const IDS_PER_SANDBOX: u32 = 2;
+
+// whether each pair of IDs is allocated to a sandbox, with the shared memory file it is mapped from
+static IN_USE: OnceLock<(RawFd, &'static [AtomicBool])> = OnceLock::new();
+
+/// Create the record of which IDs are in use, or map the one passed on by the previous zygote.
+/// Must be called in the zygote before any sandboxes are created.
+pub fn init() {
+    let Some(pairs) = max_sandboxes() else {
+        return;
+    };
+    let fd = match lifecycle::take_inherited_fd(lifecycle::IDS_FD_VAR) {
+        Some(fd) => {
+            let size = fstat(fd).expect("error checking sandbox ID record").st_size;
+            assert_eq!(size, pairs as i64, "$ATO_SANDBOX_IDS has changed");
+            fd
+        }
+        None => {
+            let fd = memfd_create(c"ATO sandbox IDs", MemFdCreateFlag::MFD_CLOEXEC)
+                .expect("error creating sandbox ID record");
+            // the new space is zero-filled, i.e. every ID is free
+            ftruncate(fd, pairs as i64).expect("error resizing sandbox ID record");
+            fd
+        }
+    };
+    // this is safe because we don't ask for a specific address, and the file is the right size
+    let ptr = unsafe {
+        mmap(
+            None,
+            NonZeroUsize::new(pairs).unwrap(),
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_SHARED,
+            fd,
+            0,
+        )
+    }
+    .expect("error mapping sandbox ID record");
+    // the mapping is never unmapped, and AtomicBool has the same layout as a byte which is 0 or 1
+    let in_use = unsafe { std::slice::from_raw_parts(ptr as *const AtomicBool, pairs) };
+    if IN_USE.set((fd, in_use)).is_err() {
+        panic!("sandbox ID record initialised twice");
+    }
+}
+
+/// the file descriptor of the record of which IDs are in use, to pass on to a new zygote
+pub fn fd() -> Option<RawFd> {
+    IN_USE.get().map(|(fd, _)| *fd)
+}
+
+fn in_use() -> &'static [AtomicBool] {
+    IN_USE.get().expect("sandbox ID record not initialised").1
+}
+
+/// The outside user and group IDs of one sandbox, which are released when dropped.
+pub struct Ids {
+    pub uid: Uid,
+    pub gid: Gid,
+    /// for the unprivileged user inside the container
+    pub unprivileged: Option<(Uid, Gid)>,
+    // the pair's entry in IN_USE
+    allocated: Option<&'static AtomicBool>,
+}
+
+impl Ids {
+    pub fn allocate() -> Result<Self, Error> {
+        let Some((first, _)) = *RANGE else {
+            return Ok(Self {
+                uid: Uid::current(),
+                gid: Gid::current(),
+                unprivileged: None,
+                allocated: None,
+            });
+        };
+        Self::allocate_from(first, in_use())
+    }
+
+    /// take the first free pair in the record, where the first pair starts at `first`
+    fn allocate_from(first: u32, in_use: &'static [AtomicBool]) -> Result<Self, Error> {
+        let index = in_use
+            .iter()
+            .position(|pair| pair.compare_exchange(false, true, SeqCst, SeqCst).is_ok())
+            .ok_or_else(|| {
+                Error::InternalError("error allocating sandbox IDs: they are all in use".into())
+            })?;
+        let id = first + index as u32 * IDS_PER_SANDBOX;
+        Ok(Self {
+            uid: Uid::from_raw(id),
+            gid: Gid::from_raw(id),
+            unprivileged: Some((Uid::from_raw(id + 1), Gid::from_raw(id + 1))),
+            allocated: Some(&in_use[index]),
+        })
+    }
+}
+
+impl Drop for Ids {
+    fn drop(&mut self) {
+        if let Some(pair) = self.allocated {
+            pair.store(false, SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pairs: usize) -> &'static [AtomicBool] {
+        Box::leak((0..pairs).map(|_| AtomicBool::new(false)).collect())
+    }
+
+    fn uids(ids: &Ids) -> (u32, Option<u32>) {
+        (
+            ids.uid.as_raw(),
+            ids.unprivileged.map(|(uid, _)| uid.as_raw()),
+        )
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("X", "100000:2048"), (100000, 2048));
+        for invalid in [
+            "100000",
+            "0:2048",
+            "100000:1",
+            "4294967295:2",
+            "a:2",
+            "1:-2",
+        ] {
+            let result = std::panic::catch_unwind(|| parse_range("X", invalid));
+            assert!(result.is_err(), "{invalid} was accepted");
+        }
+    }
+
+    #[test]
+    fn self_test_range_separate() {
+        let server = Some((1000, 100));
+        assert_eq!(self_test_range(server, None), server);
+        assert_eq!(self_test_range(None, Some((1000, 100))), Some((1000, 100)));
+        assert_eq!(self_test_range(server, Some((1100, 10))), Some((1100, 10)));
+        assert_eq!(self_test_range(server, Some((990, 10))), Some((990, 10)));
+        for overlapping in [(1099, 10), (991, 10), (1010, 10), (900, 1000)] {
+            let result = std::panic::catch_unwind(|| self_test_range(server, Some(overlapping)));
+            assert!(result.is_err(), "{overlapping:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn allocate_and_release() {
+        let in_use = record(2);
+        let first = Ids::allocate_from(1000, in_use).unwrap();
+        let second = Ids::allocate_from(1000, in_use).unwrap();
+        assert_eq!(uids(&first), (1000, Some(1001)));
+        assert_eq!(uids(&second), (1002, Some(1003)));
+        assert_eq!(second.gid.as_raw(), 1002);
+        assert!(Ids::allocate_from(1000, in_use).is_err());
+        drop(first);
+        let third = Ids::allocate_from(1000, in_use).unwrap();
+        assert_eq!(uids(&third), (1000, Some(1001)));
+        drop(second);
+        drop(third);
+        assert!(in_use.iter().all(|pair| !pair.load(SeqCst)));
+    }
+}
@@ -1,12 +1,389 @@
+//! The registry of languages, which is read from `$ATO_LANGUAGES` (by default
+//! `/usr/local/share/ATO/languages.json`) when the server starts, and read again when it receives
+//! SIGHUP.
+//!
+//! Both the network process and the zygote keep their own copy. On reload, the network process
+//! parses the file first, then asks the zygote to read it too and check that every language's image
+//! is installed, and only switches to its new copy if that worked. If anything is wrong with the
+//! new file, both carry on with the old one. The zygote always reads the file itself, because it
+//! doesn't trust anything the network process could give it (see zygote.rs).
+
+use crate::{Error, auth::ApiKey, constants::*, egress, seccomp, zygote};
 use serde::Deserialize;
-use std::collections::hash_map::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::RwLock;
 
 #[derive(Deserialize)]
 pub struct Language {
+    /// the image for the default version
     pub image: String,
+    /// the name of the default version, which requests can also ask for explicitly. Required if
+    /// there are any other versions
+    #[serde(default)]
+    pub default_version: Option<String>,
+    /// other versions which requests can ask for by name
+    #[serde(default)]
+    pub versions: BTreeMap<String, NamedVersion>,
+    /// system calls to deny as well as the defaults (see seccomp.rs)
+    #[serde(default)]
+    pub deny_syscalls: Vec<String>,
+    /// system calls to allow even though they are denied by default
+    #[serde(default)]
+    pub allow_syscalls: Vec<String>,
+    /// whether the runner can switch to an unprivileged user with /ATO/unprivileged, unless the
+    /// request says otherwise
+    #[serde(default)]
+    pub unprivileged_user: bool,
+    /// allows outbound network access through the egress proxy, unless the API key has its own
+    /// policy
+    #[serde(default)]
+    pub egress: Option<egress::Policy>,
+    /// other IDs which can be used for this language, like `python3` for `python`
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// like aliases, but requests using them get a warning telling them to use the language's own
+    /// ID instead. Used for IDs which have been renamed, so that old links keep working
+    #[serde(default)]
+    pub deprecated_aliases: Vec<String>,
+    /// limits which apply to this language instead of the defaults
+    #[serde(default)]
+    pub limits: LanguageLimits,
+    /// passed to the compiler or interpreter when the request doesn't give any options
+    #[serde(default)]
+    pub default_options: Vec<String>,
+    /// a Bash script which prints the exact version of the compiler or interpreter (see versions.rs)
+    #[serde(default)]
+    pub version_probe: Option<String>,
+    /// used by the self-test (see selftest.rs)
+    #[serde(default)]
+    pub hello_world: Option<HelloWorld>,
     // serde silently ignores the extra fields, which we don't use
 }
 
-lazy_static::lazy_static! {
-    pub static ref LANGUAGES: HashMap<String, Language> = serde_json::from_str(include_str!("../languages.json")).expect("languages.json is invalid");
+#[derive(Deserialize)]
+pub struct HelloWorld {
+    pub code: String,
+    #[serde(default)]
+    pub input: String,
+    #[serde(default)]
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    /// the expected stdout (stderr is ignored)
+    pub output: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageLimits {
+    /// in seconds; used when the request doesn't give a timeout
+    pub timeout: Option<i32>,
+    /// in seconds
+    pub max_timeout: Option<i32>,
+    /// in MiB; used when the request doesn't give a memory limit
+    pub memory: Option<u64>,
+    /// in MiB
+    pub max_memory: Option<u64>,
+    /// number of processes and threads; used when the request doesn't give a limit
+    pub pids: Option<u64>,
+    pub max_pids: Option<u64>,
+    /// in KiB, for each of stdout and stderr
+    #[serde(default = "default_output")]
+    pub output: u64,
+    /// in KiB, the size of each of the sandbox's writable file systems
+    #[serde(default = "default_tmpfs")]
+    pub tmpfs: u64,
+    /// in MiB, the largest file the program can write
+    #[serde(default = "default_file_size")]
+    pub file_size: u64,
+}
+
+impl Default for LanguageLimits {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            max_timeout: None,
+            memory: None,
+            max_memory: None,
+            pids: None,
+            max_pids: None,
+            output: default_output(),
+            tmpfs: default_tmpfs(),
+            file_size: default_file_size(),
+        }
+    }
+}
+
+impl LanguageLimits {
+    /// The highest timeout a request for the language can ask for, in seconds.
+    pub fn max_timeout(&self, key: &ApiKey) -> i32 {
+        maximum(self.max_timeout, key.max_timeout, DEFAULT_MAX_TIMEOUT)
+    }
+
+    /// The highest memory limit a request for the language can ask for, in MiB.
+    pub fn max_memory(&self, key: &ApiKey) -> u64 {
+        maximum(self.max_memory, key.max_memory, DEFAULT_MAX_MEMORY)
+    }
+
+    /// The highest number of processes and threads a request for the language can ask for.
+    pub fn max_pids(&self, key: &ApiKey) -> u64 {
+        maximum(self.max_pids, key.max_pids, DEFAULT_MAX_PIDS)
+    }
+}
+
+/// A language's maximum replaces the default one, so it can be higher or lower, and an API key's
+/// maximum replaces the default for languages which don't have their own, and caps those which do.
+fn maximum<T: Ord>(language: Option<T>, key: Option<T>, default: T) -> T {
+    match (language, key) {
+        (Some(language), Some(key)) => language.min(key),
+        (language, key) => language.or(key).unwrap_or(default),
+    }
+}
+
+fn default_tmpfs() -> u64 {
+    655350
+}
+
+fn default_file_size() -> u64 {
+    128
+}
+
+fn default_output() -> u64 {
+    128
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamedVersion {
+    pub image: String,
+    /// the runner to use instead of the language's own, as named in runners/
+    #[serde(default)]
+    pub runner: Option<String>,
+}
+
+/// The image and runner used for one version of a language.
+pub struct Version<'a> {
+    /// None for the default version of a language which doesn't name its versions
+    pub name: Option<&'a str>,
+    pub image: &'a str,
+    /// as named in runners/
+    pub runner: &'a str,
+}
+
+impl Language {
+    /// The version to run for a request which asks for `requested`, or the default version if it
+    /// doesn't ask for one. Returns None if there is no such version.
+    pub fn version<'a>(&'a self, id: &'a str, requested: Option<&str>) -> Option<Version<'a>> {
+        match requested {
+            Some(name) if Some(name) != self.default_version.as_deref() => {
+                let (name, version) = self.versions.get_key_value(name)?;
+                Some(Version {
+                    name: Some(name),
+                    image: &version.image,
+                    runner: version.runner.as_deref().unwrap_or(id),
+                })
+            }
+            _ => Some(Version {
+                name: self.default_version.as_deref(),
+                image: &self.image,
+                runner: id,
+            }),
+        }
+    }
+
+    pub fn all_versions<'a>(&'a self, id: &'a str) -> impl Iterator<Item = Version<'a>> {
+        let names = std::iter::once(None).chain(self.versions.keys().map(|name| Some(&**name)));
+        names.map(|name| self.version(id, name).unwrap())
+    }
+}
+
+impl Version<'_> {
+    /// where the image's root file system is mounted
+    pub fn rootfs(&self) -> String {
+        const IMAGE_BASE_PATH: &str = "/usr/local/lib/ATO/rootfs/";
+        String::from(IMAGE_BASE_PATH) + &self.image_path_safe()
+    }
+
+    /// where the image's environment variables are stored
+    pub fn env_file(&self) -> String {
+        const ENV_BASE_PATH: &str = "/usr/local/lib/ATO/env/";
+        String::from(ENV_BASE_PATH) + &self.image_path_safe()
+    }
+
+    /// where the digest of the installed image is stored, if it was recorded (see versions.rs)
+    pub fn digest_file(&self) -> String {
+        const DIGEST_BASE_PATH: &str = "/usr/local/lib/ATO/digest/";
+        String::from(DIGEST_BASE_PATH) + &self.image_path_safe()
+    }
+
+    fn image_path_safe(&self) -> String {
+        self.image.replace("/", "+").replace(":", "+")
+    }
+
+    /// where the runner is installed
+    pub fn runner_path(&self) -> String {
+        const LANGUAGE_BASE_PATH: &str = "/usr/local/share/ATO/runners/";
+        String::from(LANGUAGE_BASE_PATH) + self.runner
+    }
+}
+
+pub struct Languages {
+    languages: HashMap<String, Language>,
+    aliases: HashMap<String, Alias>,
+    // the whole file, including the fields only the frontend uses
+    catalogue: serde_json::Map<String, serde_json::Value>,
+}
+
+/// another ID for a language
+pub struct Alias {
+    /// the language's own ID
+    pub id: String,
+    pub deprecated: bool,
+}
+
+impl Languages {
+    fn new(
+        languages: HashMap<String, Language>,
+        catalogue: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Self, String> {
+        let mut aliases = HashMap::new();
+        for (id, language) in &languages {
+            match &language.default_version {
+                None if !language.versions.is_empty() => {
+                    return Err(format!("{id} has other versions, but no default_version"));
+                }
+                Some(name) if language.versions.contains_key(name) => {
+                    return Err(format!("{id}'s default version {name} is also in versions"));
+                }
+                _ => (),
+            }
+            if language
+                .default_options
+                .iter()
+                .any(|arg| arg.contains('\0'))
+            {
+                return Err(format!("default option for {id} contains null byte"));
+            }
+            let all = (language.aliases.iter().map(|alias| (alias, false))).chain(
+                language
+                    .deprecated_aliases
+                    .iter()
+                    .map(|alias| (alias, true)),
+            );
+            for (alias, deprecated) in all {
+                if languages.contains_key(alias) || aliases.contains_key(alias) {
+                    return Err(format!("alias {alias} for {id} is already used"));
+                }
+                let id = id.clone();
+                aliases.insert(alias.clone(), Alias { id, deprecated });
+            }
+        }
+        Ok(Self {
+            languages,
+            aliases,
+            catalogue,
+        })
+    }
+
+    /// Look up a language by its own ID (not an alias).
+    pub fn get(&self, id: &str) -> Option<&Language> {
+        self.languages.get(id)
+    }
+
+    pub fn alias(&self, id: &str) -> Option<&Alias> {
+        self.aliases.get(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Language)> {
+        self.languages.iter()
+    }
+
+    /// The file as it was read, for the frontend (see versions.rs).
+    pub fn catalogue(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.catalogue
+    }
+}
+
+// registries which have been replaced are leaked, because requests which are still running may
+// refer to them. They are small, and only replaced when an admin asks
+static CURRENT: RwLock<Option<&'static Languages>> = RwLock::new(None);
+
+/// The current registry of languages.
+pub fn get() -> &'static Languages {
+    CURRENT
+        .read()
+        .unwrap()
+        .expect("languages have not been loaded")
+}
+
+fn set(languages: Languages) {
+    *CURRENT.write().unwrap() = Some(Box::leak(Box::new(languages)));
+}
+
+/// Read and parse the file, without checking that the images are installed.
+fn read() -> Result<Languages, String> {
+    let path = std::env::var("ATO_LANGUAGES").unwrap_or_else(|e| {
+        if let std::env::VarError::NotUnicode(_) = e {
+            panic!("$ATO_LANGUAGES is invalid Unicode")
+        }
+        "/usr/local/share/ATO/languages.json".to_string()
+    });
+    let data = std::fs::read(&path).map_err(|e| format!("error reading {path}: {e}"))?;
+    let invalid = |e| format!("{path} is invalid: {e}");
+    let languages = serde_json::from_slice(&data).map_err(invalid)?;
+    let catalogue = serde_json::from_slice(&data).map_err(invalid)?;
+    seccomp::check_languages(&languages).map_err(|e| format!("{path}: {e}"))?;
+    Languages::new(languages, catalogue).map_err(|e| format!("{path}: {e}"))
+}
+
+/// Make sure everything each language needs to run is installed.
+fn check_installed(languages: &Languages) -> Result<(), String> {
+    for (id, language) in languages.iter() {
+        for version in language.all_versions(id) {
+            for (what, path) in [
+                ("root file system", version.rootfs()),
+                ("environment file", version.env_file()),
+                ("runner", version.runner_path()),
+            ] {
+                if !Path::new(&path).exists() {
+                    let id = match version.name {
+                        Some(name) => format!("{id} version {name}"),
+                        None => id.clone(),
+                    };
+                    return Err(format!("{what} for {id} not found: {path}"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read and check the file when the server starts, failing if anything is wrong with it. Must be
+/// called before the zygote is started, so that the zygote has a copy too.
+pub fn load() {
+    let languages = read()
+        .and_then(|languages| check_installed(&languages).map(|()| languages))
+        .unwrap_or_else(|e| panic!("{e}"));
+    set(languages);
+}
+
+/// Read the file again, and make it the current registry if it is valid, in both this process and
+/// the zygote.
+pub fn reload() -> Result<(), Error> {
+    let languages = read().map_err(Error::InternalError)?;
+    zygote::reload_languages()?;
+    let count = languages.languages.len();
+    set(languages);
+    eprintln!("loaded {count} languages");
+    Ok(())
+}
+
+/// The zygote's side of reload.
+pub fn reload_in_zygote() -> Result<(), Error> {
+    let languages = read()
+        .and_then(|languages| check_installed(&languages).map(|()| languages))
+        .map_err(Error::InternalError)?;
+    set(languages);
+    Ok(())
 }
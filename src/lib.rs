@@ -0,0 +1,387 @@
+//! Attempt This Online's server and sandbox, and the parts shared with the `ato` command-line
+//! client. See [`embed`] for running programs in the sandbox from other Rust programs.
+
+#![feature(io_error_more, cursor_split)]
+
+mod auth;
+pub mod cli;
+mod client;
+mod constants;
+mod egress;
+pub mod embed;
+mod ids;
+mod languages;
+mod lifecycle;
+mod local;
+mod network;
+mod origin;
+pub mod protocol;
+mod queue;
+mod ratelimit;
+mod sandbox;
+mod seccomp;
+mod selftest;
+mod server;
+mod shared;
+mod versions;
+mod worker;
+mod zygote;
+
+use crate::{client::Client, constants::*, languages::*};
+use nix::sys::signal::{SigHandler, Signal, signal};
+pub use protocol::{ControlMessage, Request, StreamResponse};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::cell::Cell;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::sync::LazyLock;
+use tungstenite as ws;
+use tungstenite::handshake::server as http;
+use tungstenite::http::StatusCode;
+use tungstenite::protocol::frame::{CloseFrame, coding::CloseCode};
+
+fn get_bind_address() -> SocketAddr {
+    use std::str::FromStr;
+    SocketAddr::from_str(&std::env::var("ATO_BIND").unwrap_or_else(|e| {
+        if let std::env::VarError::NotUnicode(_) = e {
+            panic!("$ATO_BIND is invalid Unicode")
+        }
+        "127.0.0.1:8500".to_string()
+    }))
+    .expect("$ATO_BIND is not a valid address")
+}
+
+/// The entry point of the server binary.
+pub fn main() {
+    // a zygote started by the old one during an upgrade doesn't return from this
+    zygote::resume();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut self_test = false;
+    let mut local_request = None;
+    match args.first().map(String::as_str) {
+        None => (),
+        Some("self-test") => self_test = true,
+        Some("run") => local_request = Some(local::parse_args(&args[1..])),
+        Some(_) => {
+            eprintln!("{}", local::usage());
+            std::process::exit(2);
+        }
+    }
+
+    // tell the kernel not to keep zombie processes around
+    // see waitpid(2) § NOTES and https://elixir.bootlin.com/linux/v6.1.2/source/kernel/signal.c#L2089
+    // this is safe because there was no previous signal handler function
+    unsafe { signal(Signal::SIGCHLD, SigHandler::SigIgn) }.unwrap();
+
+    shared::init();
+    queue::init();
+    // read configuration now, so that any errors in it are found straight away
+    LazyLock::force(&client::TRUSTED_PROXIES);
+    LazyLock::force(&ratelimit::LIMIT);
+    LazyLock::force(&auth::KEYS);
+    LazyLock::force(&origin::ALLOWED_ORIGINS);
+    if self_test {
+        ids::use_self_test_range();
+    }
+    LazyLock::force(&ids::RANGE);
+    languages::load();
+    versions::load();
+
+    // before anything else is opened, and before there are any threads
+    zygote::start();
+    if self_test {
+        let passed = selftest::run(&args[1..]);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if let Some(request) = local_request {
+        std::process::exit(local::run(request));
+    }
+    let lifecycle = lifecycle::Lifecycle::new();
+    let listener = lifecycle::get_listener(get_bind_address);
+    server::run(listener, lifecycle);
+}
+
+/// how to close the websocket after an error, or None if the client has gone away already
+fn close_frame(error: Error) -> Option<CloseFrame<'static>> {
+    let (code, reason) = match error {
+        Error::ClientWentAway => return None,
+        Error::TooLarge(size) => (
+            CloseCode::Size,
+            format!("received message of size {size}, greater than size limit {MAX_REQUEST_SIZE}"),
+        ),
+        Error::UnsupportedData => (CloseCode::Unsupported, "expected a binary message".into()),
+        Error::PolicyViolation(e) => (CloseCode::Policy, format!("invalid request: {e}")),
+        Error::TryAgainLater(e) => (CloseCode::Again, e),
+        Error::ShuttingDown => (CloseCode::Restart, "server is shutting down".into()),
+        Error::InternalError(e) => {
+            eprintln!("{e}");
+            (CloseCode::Error, e)
+        }
+    };
+    Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    })
+}
+
+fn send_bad_request(mut connection: TcpStream, error: ws::Error) {
+    use std::io::Write;
+    let body = format!("invalid websocket handshake: {error}");
+    let response = format!(
+        "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = connection.write_all(response.as_bytes()) {
+        // can't do anything but log it
+        eprintln!("error sending bad request response: {e}");
+    }
+}
+
+/// passes extra information in and out of handle_headers
+pub struct HandshakeCallback {
+    pub peer: IpAddr,
+    pub client: Rc<Cell<Option<Client>>>,
+}
+
+impl http::Callback for HandshakeCallback {
+    fn on_request(
+        self,
+        request: &http::Request,
+        response: http::Response,
+    ) -> Result<http::Response, http::ErrorResponse> {
+        let mut client = None;
+        let result = handle_headers(request, response, self.peer, &mut client);
+        self.client.set(client);
+        result
+    }
+}
+
+fn handle_headers(
+    request: &http::Request,
+    response: http::Response,
+    peer: IpAddr,
+    client: &mut Option<Client>,
+) -> Result<http::Response, http::ErrorResponse> {
+    if request.uri() != "/api/v1/ws/execute" {
+        let response = http::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Some(
+                "the only supported API URL is /api/v1/ws/execute".to_string(),
+            ))
+            .unwrap();
+        return Err(response);
+    }
+    let Some(new_client) = Client::from_request(peer, request) else {
+        let response = http::Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header("WWW-Authenticate", "Bearer")
+            .body(Some("invalid API key".to_string()))
+            .unwrap();
+        return Err(response);
+    };
+    // browsers always send Origin, so this stops other websites making their visitors' browsers run
+    // code. Clients with API keys are trusted not to do that
+    if new_client.api_key.is_none()
+        && let Some(origin) = request.headers().get("Origin")
+        && !origin.to_str().is_ok_and(origin::is_allowed)
+    {
+        let response = http::Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Some("origin not allowed".to_string()))
+            .unwrap();
+        return Err(response);
+    }
+    if let Err(wait) = ratelimit::check(&new_client) {
+        let response = http::Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", wait.to_string())
+            .body(Some(format!(
+                "rate limit exceeded; try again in {wait} seconds"
+            )))
+            .unwrap();
+        return Err(response);
+    }
+    *client = Some(new_client);
+    Ok(response)
+}
+
+const DEFAULT_TIMEOUT: i32 = 60;
+
+/// Resource limits for a request, after taking into account the defaults and maximums which apply
+/// to it.
+#[derive(Debug)]
+pub struct Limits {
+    /// in seconds
+    pub timeout: i32,
+    /// in bytes
+    pub memory: u64,
+    /// number of processes and threads
+    pub pids: u64,
+    /// in bytes, for each of stdout and stderr
+    pub output: usize,
+    /// in bytes, the size of each of the sandbox's writable file systems
+    pub tmpfs: u64,
+    /// in bytes, the largest file the program can write
+    pub file_size: u64,
+    /// the sandbox gets an egress proxy if this is given
+    pub egress: Option<&'static egress::Policy>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Error {
+    ClientWentAway,
+    TooLarge(usize),
+    UnsupportedData,
+    PolicyViolation(String),
+    TryAgainLater(String),
+    ShuttingDown,
+    InternalError(String),
+}
+
+/// like the ? postfix operator, but formats errors to strings
+macro_rules! check {
+    ($x:expr, $f:literal $(, $($a:expr),+)? $(,)?) => {
+        $x.map_err(|e| Error::InternalError(format!($f, $($($a,)*)? e)))?
+    };
+    ($x:expr $(,)?) => {
+        $x.map_err(|e| Error::InternalError(e.to_string()))?
+    }
+}
+
+pub(crate) use check;
+
+/// If the request uses an alias for its language, replace it with the language's own ID, and if it
+/// doesn't ask for a version, fill in the language's default. Returns a warning for the client if
+/// the alias is deprecated.
+pub fn resolve_language(request: &mut Request) -> Option<String> {
+    let languages = languages::get();
+    let mut warning = None;
+    if let Some(alias) = languages.alias(&request.language) {
+        if alias.deprecated {
+            warning = Some(format!(
+                "language {} is deprecated; use {} instead",
+                request.language, alias.id
+            ));
+        }
+        request.language = alias.id.clone();
+    }
+    if request.version.is_none()
+        && let Some(language) = languages.get(&request.language)
+    {
+        request.version = language.default_version.clone();
+    }
+    warning
+}
+
+pub fn validate(request: &Request, client: &Client) -> Result<(&'static Language, Limits), Error> {
+    let permissions = client.limits();
+    let Some(language) = languages::get().get(&request.language) else {
+        return Err(Error::PolicyViolation(format!(
+            "no such language: {}",
+            &request.language
+        )));
+    };
+    let language_limits = &language.limits;
+    let max_timeout = language_limits.max_timeout(permissions);
+    let timeout = request.timeout.unwrap_or_else(|| {
+        language_limits
+            .timeout
+            .unwrap_or(DEFAULT_TIMEOUT)
+            .min(max_timeout)
+    });
+    if timeout < 1 || timeout > max_timeout {
+        return Err(Error::PolicyViolation(format!(
+            "timeout not in range 1-{max_timeout}: {timeout}"
+        )));
+    }
+    let max_memory = language_limits.max_memory(permissions);
+    let memory = request
+        .memory
+        .unwrap_or_else(|| language_limits.memory.unwrap_or(max_memory).min(max_memory));
+    if memory < 1 || memory > max_memory {
+        return Err(Error::PolicyViolation(format!(
+            "memory not in range 1-{max_memory}: {memory}"
+        )));
+    }
+    let max_pids = language_limits.max_pids(permissions);
+    let pids = request
+        .pids
+        .unwrap_or_else(|| language_limits.pids.unwrap_or(max_pids).min(max_pids));
+    if pids < 1 || pids > max_pids {
+        return Err(Error::PolicyViolation(format!(
+            "pids not in range 1-{max_pids}: {pids}"
+        )));
+    }
+    if request.custom_runner.is_some() && !permissions.custom_runners {
+        return Err(Error::PolicyViolation(
+            "custom runners are not allowed".to_string(),
+        ));
+    }
+    if let Some(languages) = &permissions.languages
+        && !languages.contains(&request.language)
+    {
+        return Err(Error::PolicyViolation(format!(
+            "language not allowed: {}",
+            request.language
+        )));
+    }
+    for arg in request.options.iter().chain(request.arguments.iter()) {
+        if arg.contains(&0) {
+            return Err(Error::PolicyViolation(
+                "argument contains null byte".to_string(),
+            ));
+        }
+    }
+    if language
+        .version(&request.language, request.version.as_deref())
+        .is_none()
+    {
+        return Err(Error::PolicyViolation(format!(
+            "no such version of {}: {}",
+            request.language,
+            request.version.as_deref().unwrap_or_default()
+        )));
+    }
+    if request
+        .unprivileged_user
+        .unwrap_or(language.unprivileged_user)
+        && ids::RANGE.is_none()
+    {
+        return Err(Error::PolicyViolation(
+            "this server can't run programs as an unprivileged user".to_string(),
+        ));
+    }
+    let limits = Limits {
+        timeout,
+        memory: memory * MiB,
+        pids,
+        output: (language_limits.output * KiB) as usize,
+        tmpfs: language_limits.tmpfs * KiB,
+        file_size: language_limits.file_size * MiB,
+        egress: permissions.egress.as_ref().or(language.egress.as_ref()),
+    };
+    Ok((language, limits))
+}
+
+/// decode a msgpack message from the client, rejecting any extra data after it
+pub fn decode_message<T: DeserializeOwned>(message: &[u8]) -> Result<T, Error> {
+    let cursor = std::io::Cursor::new(message);
+    let mut de = rmp_serde::Deserializer::new(cursor);
+    match <T as Deserialize>::deserialize(&mut de) {
+        Ok(r) => {
+            if !de.get_ref().split().1.is_empty() {
+                Err(Error::PolicyViolation("found extra data".to_string()))
+            } else {
+                Ok(r)
+            }
+        }
+        Err(e) => Err(Error::PolicyViolation(e.to_string())),
+    }
+}
+
+pub fn encode_message<T: Serialize>(message: T) -> Result<Vec<u8>, Error> {
+    Ok(check!(
+        rmp_serde::to_vec_named(&message),
+        "error encoding output message: {}"
+    ))
+}
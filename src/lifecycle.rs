@@ -0,0 +1,308 @@
+//! Signal handling for the main server process: graceful shutdown on SIGTERM, replacing the
+//! running binary with a new version on SIGUSR2 without dropping any connections, and reloading the
+//! languages file on SIGHUP.
+
+use crate::{shared, zygote};
+use nix::{
+    errno::Errno,
+    fcntl::{FcntlArg, FdFlag, OFlag, fcntl},
+    sys::{
+        signal::{SigSet, Signal, kill},
+        signalfd::{SfdFlags, SignalFd},
+    },
+    unistd::{Pid, close, pipe2, read},
+};
+use std::ffi::OsString;
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+
+/// environment variables used to pass file descriptors on to the new version of the server
+const LISTENER_FD_VAR: &str = "ATO_LISTENER_FD";
+const SHUTDOWN_FDS_VAR: &str = "ATO_SHUTDOWN_FDS";
+const EXIT_FDS_VAR: &str = "ATO_EXIT_FDS";
+pub const SHARED_FD_VAR: &str = "ATO_SHARED_FD";
+pub const LAUNCHER_FD_VAR: &str = "ATO_LAUNCHER_FD";
+/// environment variables used by the old zygote to pass file descriptors on to the new one
+pub const ZYGOTE_FD_VAR: &str = "ATO_ZYGOTE_FD";
+pub const IDS_FD_VAR: &str = "ATO_IDS_FD";
+
+/// Take a file descriptor passed on by the previous version of the server, if there was one.
+///
+/// Must be called before any threads are started.
+pub fn take_inherited_fd(var: &str) -> Option<RawFd> {
+    let value = std::env::var(var).ok()?;
+    // this is safe because there are no other threads yet. The variable is removed so that it
+    // isn't passed on to anything else by accident
+    unsafe { std::env::remove_var(var) };
+    let fd = value
+        .parse()
+        .unwrap_or_else(|_| panic!("${var} is not a valid file descriptor"));
+    // the fd had to be inheritable to survive exec, but nothing else should inherit it
+    set_cloexec(fd, true);
+    Some(fd)
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) {
+    let flags = if cloexec {
+        FdFlag::FD_CLOEXEC
+    } else {
+        FdFlag::empty()
+    };
+    fcntl(fd, FcntlArg::F_SETFD(flags)).expect("error setting close-on-exec flag");
+}
+
+fn handled_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGUSR2);
+    signals.add(Signal::SIGHUP);
+    signals
+}
+
+pub enum Event {
+    Shutdown,
+    Upgrade,
+    ReloadLanguages,
+}
+
+/// what happened to this process after trying to upgrade
+pub enum Upgraded {
+    /// the new binary couldn't be started, so carry on as normal
+    Failed,
+    /// the new version has taken over the listener, so this process should finish the requests it
+    /// already has, without accepting any more
+    Draining,
+}
+
+pub struct Lifecycle {
+    signals: SignalFd,
+    // processes left over from previous versions of the server hold the read end of this pipe, and
+    // the current main process holds the write end. When the main process closes it, they see a
+    // hangup and know to shut down too
+    shutdown_read: RawFd,
+    shutdown_write: Option<RawFd>,
+    // every version of the server holds the write end of this pipe until it exits, so the current
+    // main process can tell when the old ones have all finished
+    exit_read: RawFd,
+    exit_write: Option<RawFd>,
+    // the new version of the server, once this one has been replaced
+    successor: Option<Pid>,
+}
+
+impl Lifecycle {
+    /// Start handling signals. Must be called before any threads are started.
+    pub fn new() -> Self {
+        let signals = handled_signals();
+        // signals are received through a signalfd, so that they can be handled in the event loop
+        // without any race conditions
+        signals.thread_block().expect("error blocking signals");
+        let signals =
+            SignalFd::with_flags(&signals, SfdFlags::SFD_CLOEXEC).expect("error creating signalfd");
+        let (shutdown_read, shutdown_write) = take_inherited_pair(SHUTDOWN_FDS_VAR)
+            .unwrap_or_else(|| pipe2(OFlag::O_CLOEXEC).expect("error creating shutdown pipe"));
+        let (exit_read, exit_write) = take_inherited_pair(EXIT_FDS_VAR)
+            .unwrap_or_else(|| pipe2(OFlag::O_CLOEXEC).expect("error creating exit pipe"));
+        Self {
+            signals,
+            shutdown_read,
+            shutdown_write: Some(shutdown_write),
+            exit_read,
+            exit_write: Some(exit_write),
+            successor: None,
+        }
+    }
+
+    /// the file descriptor which becomes readable when a signal arrives
+    pub fn signal_fd(&self) -> RawFd {
+        self.signals.as_raw_fd()
+    }
+
+    /// the file descriptor which hangs up when the current version of the server shuts down
+    pub fn shutdown_fd(&self) -> RawFd {
+        self.shutdown_read
+    }
+
+    /// whether a new version of the server has taken over from this one
+    pub fn is_replaced(&self) -> bool {
+        self.successor.is_some()
+    }
+
+    pub fn read_signal(&mut self) -> Option<Event> {
+        let info = match self.signals.read_signal() {
+            Ok(Some(info)) => info,
+            Ok(None) => return None,
+            Err(e) => panic!("error reading signal: {e}"),
+        };
+        let signal = Signal::try_from(info.ssi_signo as i32).ok()?;
+        if let Some(successor) = self.successor {
+            // it was sent before systemd found out about the new version, so pass it on
+            if let Err(e) = kill(successor, signal) {
+                eprintln!("error passing {signal} on to the new version of the server: {e}");
+            }
+            return None;
+        }
+        match signal {
+            Signal::SIGTERM => Some(Event::Shutdown),
+            Signal::SIGUSR2 => Some(Event::Upgrade),
+            Signal::SIGHUP => Some(Event::ReloadLanguages),
+            _ => None,
+        }
+    }
+
+    /// Tell any processes left over from previous versions of the server to shut down as well, and
+    /// tell systemd that shutting down may take up to `duration`, which replaces `TimeoutStopSec` if
+    /// it is longer.
+    pub fn start_shutdown(&mut self, duration: Duration) {
+        if let Some(fd) = self.shutdown_write.take() {
+            let _ = close(fd);
+            notify(&format!(
+                "STOPPING=1\nEXTEND_TIMEOUT_USEC={}",
+                duration.as_micros()
+            ));
+        }
+    }
+
+    /// Start a fresh copy of the server binary, passing on the listener and shared memory. It takes
+    /// over as the main process, while this one carries on running the requests it already has.
+    ///
+    /// The caller must stop using the listener if this returns Draining.
+    pub fn upgrade(&mut self, listener: &TcpListener) -> Upgraded {
+        eprintln!("upgrading server");
+        let (Some(shutdown_write), Some(exit_write)) = (self.shutdown_write, self.exit_write)
+        else {
+            return Upgraded::Failed;
+        };
+        // the new version can't start a zygote of its own, because this process has dropped the
+        // privileges needed to create sandboxes, so ours starts one from the new binary for it
+        let launcher = match zygote::replace() {
+            Ok(launcher) => launcher,
+            Err(e) => {
+                eprintln!("error starting new zygote: {e:?}");
+                return Upgraded::Failed;
+            }
+        };
+        let fds = [
+            (LISTENER_FD_VAR, &[listener.as_raw_fd()][..]),
+            (SHUTDOWN_FDS_VAR, &[self.shutdown_read, shutdown_write]),
+            (EXIT_FDS_VAR, &[self.exit_read, exit_write]),
+            (SHARED_FD_VAR, &[shared::fd()]),
+            (LAUNCHER_FD_VAR, &[launcher.as_raw_fd()]),
+        ];
+        // signals which arrive before the new version has set up its signalfd wait for it, rather
+        // than killing it
+        let successor = match spawn_binary(std::env::args_os().skip(1), &fds, handled_signals()) {
+            Ok(pid) => pid,
+            Err(e) => {
+                eprintln!("error starting new server binary: {e}");
+                return Upgraded::Failed;
+            }
+        };
+        // so that systemd sends signals to the new version, and waits for it to exit when stopping
+        notify(&format!("MAINPID={successor}"));
+        self.successor = Some(successor);
+        let _ = close(shutdown_write);
+        self.shutdown_write = None;
+        Upgraded::Draining
+    }
+
+    /// Wait for any processes left over from previous versions of the server to exit.
+    pub fn wait_for_old_versions(&mut self) {
+        if let Some(fd) = self.exit_write.take() {
+            let _ = close(fd);
+        }
+        let mut buf = [0];
+        loop {
+            match read(self.exit_read, &mut buf) {
+                Ok(0) => break,
+                Ok(_) | Err(Errno::EINTR) => (),
+                Err(e) => panic!("error waiting for old versions of the server: {e}"),
+            }
+        }
+    }
+}
+
+/// send a message to systemd (see sd_notify(3)), if it started the server
+fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.into_vec();
+    let result = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(std::ffi::OsString::from_vec(path)),
+    }
+    .and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(e) = result {
+        eprintln!("error notifying systemd: {e}");
+    }
+}
+
+fn take_inherited_pair(var: &str) -> Option<(RawFd, RawFd)> {
+    let value = std::env::var(var).ok()?;
+    // this is safe because there are no other threads yet
+    unsafe { std::env::remove_var(var) };
+    let parse = |fd: Option<&str>| -> RawFd {
+        fd.and_then(|fd| fd.parse().ok())
+            .unwrap_or_else(|| panic!("${var} is invalid"))
+    };
+    let mut fds = value.split(',');
+    let (read, write) = (parse(fds.next()), parse(fds.next()));
+    set_cloexec(read, true);
+    set_cloexec(write, true);
+    Some((read, write))
+}
+
+/// Start the program's binary again (which may have been replaced with a new version) with `args`.
+/// It inherits each group of `fds`, which are listed in the environment variable paired with them,
+/// and starts with `signals` blocked.
+pub fn spawn_binary(
+    args: impl IntoIterator<Item = OsString>,
+    fds: &[(&str, &[RawFd])],
+    signals: SigSet,
+) -> std::io::Result<Pid> {
+    let mut command = Command::new(std::env::args_os().next().expect("no program name"));
+    command.args(args);
+    for (var, fds) in fds {
+        let fds: Vec<String> = fds.iter().map(|fd| fd.to_string()).collect();
+        command.env(var, fds.join(","));
+    }
+    let inherited: Vec<RawFd> = fds
+        .iter()
+        .flat_map(|(_, fds)| fds.iter().copied())
+        .collect();
+    // this is safe because the closure only makes system calls, which are async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherited {
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+            }
+            // the signal mask is kept across execve
+            signals.thread_block()?;
+            Ok(())
+        })
+    };
+    let child = command.spawn()?;
+    Ok(Pid::from_raw(child.id() as i32))
+}
+
+/// Get the listening socket, either passed on by the previous version of the server or newly bound.
+pub fn get_listener(bind: impl FnOnce() -> std::net::SocketAddr) -> TcpListener {
+    match take_inherited_fd(LISTENER_FD_VAR) {
+        Some(fd) => {
+            eprintln!("resuming ATO server after upgrade");
+            // this is safe because the previous version of the server passed on its listener
+            unsafe { TcpListener::from_raw_fd(fd) }
+        }
+        None => {
+            let addr = bind();
+            eprintln!("starting ATO server on {addr}");
+            TcpListener::bind(addr).unwrap()
+        }
+    }
+}
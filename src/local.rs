@@ -0,0 +1,112 @@
+//! The `run` subcommand, which runs one program in the sandbox without the server, writing its
+//! output straight to the terminal. This is for debugging the sandbox itself, e.g. when it breaks on
+//! a new kernel or image, without needing nginx or a websocket client.
+//!
+//! The program's stdout and stderr are written as they come, and the details from the Done message
+//! are written to stderr as JSON when it finishes. With `--verbose`, each step of setting up the
+//! sandbox is written to stderr too (see `TRACE` in sandbox.rs).
+
+use crate::cli::{REQUEST_USAGE, parse_request};
+use crate::embed::{Builder, NoControl, OutputSink};
+use crate::{Error, Request, StreamResponse, check, close_frame, sandbox};
+use std::io::Write;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// the exit status when the program couldn't be run, rather than the program's own
+const FAILURE: i32 = 125;
+
+pub fn usage() -> String {
+    format!(
+        "\
+usage: attempt-this-online [self-test [LANGUAGE...]]
+       attempt-this-online run --lang LANGUAGE [OPTION...] FILE [-- ARGUMENT...]
+
+run options:
+{REQUEST_USAGE}
+  --verbose             describe each step of setting up the sandbox"
+    )
+}
+
+/// Parse the arguments to the `run` subcommand, and set up tracing if they ask for it. This must be
+/// done before the zygote is started.
+pub fn parse_args(args: &[String]) -> Request {
+    let mut verbose = false;
+    let result = parse_request(args.iter().cloned(), |option, _| {
+        if option != "--verbose" {
+            return Ok(false);
+        }
+        verbose = true;
+        Ok(true)
+    });
+    match result {
+        Ok(request) => {
+            sandbox::TRACE.store(verbose, Relaxed);
+            request
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{}", usage());
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Writes the program's output to the terminal, and keeps the Done message for the caller.
+struct Terminal {
+    done: Option<StreamResponse>,
+}
+
+impl OutputSink for Terminal {
+    fn output(&mut self, message: StreamResponse) -> Result<(), Error> {
+        match message {
+            StreamResponse::Stdout(data) => check!(
+                std::io::stdout()
+                    .write_all(&data)
+                    .and_then(|()| std::io::stdout().flush()),
+                "error writing stdout: {}"
+            ),
+            StreamResponse::Stderr(data) => {
+                check!(
+                    std::io::stderr().write_all(&data),
+                    "error writing stderr: {}"
+                )
+            }
+            StreamResponse::Warning(warning) => eprintln!("warning: {warning}"),
+            StreamResponse::Queued { .. } => (),
+            message @ StreamResponse::Done { .. } => self.done = Some(message),
+        }
+        Ok(())
+    }
+}
+
+/// Run the request, and return the status to exit with: the program's (like a shell), or FAILURE if
+/// it couldn't be run.
+pub fn run(request: Request) -> i32 {
+    let result = Builder::from(request).build().and_then(|sandbox| {
+        if let Some(warning) = sandbox.warning() {
+            eprintln!("warning: {warning}");
+        }
+        let mut terminal = Terminal { done: None };
+        sandbox.run(&mut terminal, &mut NoControl)?;
+        Ok(terminal.done)
+    });
+    let done = match result {
+        Ok(Some(done)) => done,
+        Ok(_) => {
+            eprintln!("the sandbox finished without a result");
+            return FAILURE;
+        }
+        Err(Error::InternalError(e)) => {
+            eprintln!("{e}");
+            return FAILURE;
+        }
+        Err(e) => {
+            // the same message a client would get
+            if let Some(frame) = close_frame(e) {
+                eprintln!("{}", frame.reason);
+            }
+            return FAILURE;
+        }
+    };
+    eprintln!("{}", serde_json::to_string(&done).unwrap());
+    done.exit_status().map_or(FAILURE, i32::from)
+}
@@ -2,9 +2,12 @@ use crate::{Error, check};
 use neli::{
     consts::nl::*, consts::rtnl::*, consts::socket::*, err::*, nl::*, rtnl::*, socket::*, types::*,
 };
-use nix::unistd::getpid;
+use nix::unistd::{getpid, sethostname};
 use std::os::fd::AsRawFd;
 
+/// the host name inside the sandbox
+const HOSTNAME: &str = "ato";
+
 #[derive(Debug)]
 struct IgnorePayload;
 
@@ -31,6 +34,9 @@ fn read_nl_reply(netlink: &mut NlSocketHandle) -> Result<(), Error> {
             && let NlPayload::Ack(Nlmsgerr { error: 0, .. }) = message.nl_payload
         {
             return Ok(());
+        } else if let NlPayload::Err(Nlmsgerr { error, .. }) = message.nl_payload {
+            let e = nix::errno::Errno::from_i32(-error);
+            return Err(Error::InternalError(format!("error from netlink: {e}")));
         } else {
             let e = format!("unknown reply from netlink:\n{message:?}");
             return Err(Error::InternalError(e));
@@ -104,6 +110,46 @@ pub fn setup_network() -> Result<(), Error> {
 
     sequence_number += 1;
 
+    // add address ::1/128, unless the kernel doesn't support IPv6
+    if std::fs::exists("/proc/sys/net/ipv6").unwrap_or(false) {
+        // it may be disabled by default, depending on the host's settings
+        check!(
+            std::fs::write("/proc/sys/net/ipv6/conf/lo/disable_ipv6", "0"),
+            "error enabling IPv6 on lo: {}"
+        );
+        let mut rtattrs = RtBuffer::new();
+        for rta_type in [Ifa::Local, Ifa::Address] {
+            let mut address = vec![0; 16];
+            address[15] = 1;
+            rtattrs.push(Rtattr {
+                rta_len: 20, /* = RTA_LENGTH(sizeof(struct in6_addr)) */
+                rta_type,
+                rta_payload: address.into(),
+            });
+        }
+        let message = Ifaddrmsg {
+            ifa_family: RtAddrFamily::Inet6,
+            ifa_prefixlen: 128,
+            // there's no point doing duplicate address detection on the loopback interface
+            ifa_flags: IfaFFlags::new(&[IfaF::Permanent, IfaF::Nodad]),
+            ifa_scope: RT_SCOPE_HOST,
+            ifa_index: loopback,
+            rtattrs,
+        };
+        let message = Nlmsghdr::new(
+            None,
+            Rtm::Newaddr,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Create, NlmF::Excl, NlmF::Ack]),
+            Some(sequence_number),
+            Some(pid),
+            NlPayload::Payload(message),
+        );
+        check!(netlink.send(message), "error sending Ifaddrmsg: {}");
+        read_nl_reply(&mut netlink)?;
+
+        sequence_number += 1;
+    }
+
     // add interface
     let message = Ifinfomsg::new(
         RtAddrFamily::Unspecified,
@@ -130,5 +176,30 @@ pub fn setup_network() -> Result<(), Error> {
     check!(netlink.send(message), "error sending Ifaddrmsg: {}");
     read_nl_reply(&mut netlink)?;
 
+    // the sandbox has its own UTS namespace, so this doesn't affect anything else
+    check!(sethostname(HOSTNAME), "error setting hostname: {}");
+
+    Ok(())
+}
+
+/// Replace the image's /etc/hosts and /etc/resolv.conf with ones which match the sandbox's network.
+/// Nothing listens on the name server address, so that lookups fail straight away instead of
+/// waiting for a timeout. Must be called after pivot_root.
+pub fn setup_network_files() -> Result<(), Error> {
+    let hosts = format!(
+        "127.0.0.1 localhost {HOSTNAME}\n::1 localhost ip6-localhost ip6-loopback {HOSTNAME}\n"
+    );
+    let resolv_conf = "nameserver 127.0.0.1\noptions timeout:1 attempts:1\n";
+    check!(std::fs::create_dir_all("/etc"), "error creating /etc: {}");
+    for (path, contents) in [("/etc/hosts", &*hosts), ("/etc/resolv.conf", resolv_conf)] {
+        // the image's file may be a symlink to somewhere else, which shouldn't be written to
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(Error::InternalError(format!("error removing {path}: {e}")));
+            }
+            _ => (),
+        }
+        check!(std::fs::write(path, contents), "error writing {}: {}", path);
+    }
     Ok(())
 }
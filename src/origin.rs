@@ -0,0 +1,124 @@
+use std::sync::LazyLock;
+
+/// An allowed origin, such as `https://ato.pxeger.com`, or `https://*.pxeger.com` which allows any
+/// subdomain of `pxeger.com` (but not `pxeger.com` itself).
+#[derive(Debug)]
+pub struct Pattern {
+    scheme: String,
+    // host and optional port
+    host: String,
+    wildcard: bool,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        let (scheme, host) = pattern.split_once("://").unwrap_or_else(|| {
+            panic!("$ATO_ALLOWED_ORIGINS contains an invalid origin: {pattern}")
+        });
+        let (host, wildcard) = match host.strip_prefix('*') {
+            // keep the dot so that e.g. evilpxeger.com doesn't match *.pxeger.com
+            Some(suffix) if suffix.starts_with('.') => (suffix, true),
+            _ => (host, false),
+        };
+        Pattern {
+            scheme: scheme.to_ascii_lowercase(),
+            host: host.to_ascii_lowercase(),
+            wildcard,
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        let Some((scheme, host)) = origin.split_once("://") else {
+            return false;
+        };
+        if scheme != self.scheme {
+            return false;
+        }
+        if self.wildcard {
+            host.strip_suffix(&self.host)
+                .is_some_and(|subdomain| !subdomain.is_empty() && !subdomain.contains(['/', ':']))
+        } else {
+            host == self.host
+        }
+    }
+}
+
+fn get_allowed_origins() -> Option<Vec<Pattern>> {
+    let origins = match std::env::var("ATO_ALLOWED_ORIGINS") {
+        Ok(origins) => origins,
+        // any origin is allowed unless configured
+        Err(std::env::VarError::NotPresent) => return None,
+        Err(std::env::VarError::NotUnicode(_)) => panic!("$ATO_ALLOWED_ORIGINS is invalid Unicode"),
+    };
+    Some(
+        origins
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .map(Pattern::parse)
+            .collect(),
+    )
+}
+
+/// origins of web pages which are allowed to make requests, from $ATO_ALLOWED_ORIGINS
+pub static ALLOWED_ORIGINS: LazyLock<Option<Vec<Pattern>>> = LazyLock::new(get_allowed_origins);
+
+/// check whether a web page with the given `Origin` header is allowed to make requests
+pub fn is_allowed(origin: &str) -> bool {
+    match &*ALLOWED_ORIGINS {
+        None => true,
+        Some(patterns) => patterns.iter().any(|p| p.matches(origin)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let pattern = Pattern::parse("https://ato.pxeger.com");
+        assert!(pattern.matches("https://ato.pxeger.com"));
+        assert!(pattern.matches("HTTPS://ATO.pxeger.com"));
+        assert!(!pattern.matches("http://ato.pxeger.com"));
+        assert!(!pattern.matches("https://ato.pxeger.com:8443"));
+        assert!(!pattern.matches("https://sub.ato.pxeger.com"));
+        assert!(!pattern.matches("ato.pxeger.com"));
+    }
+
+    #[test]
+    fn wildcard_not_bare_domain() {
+        let pattern = Pattern::parse("https://*.pxeger.com");
+        assert!(!pattern.matches("https://pxeger.com"));
+        assert!(!pattern.matches("https://.pxeger.com"));
+    }
+
+    #[test]
+    fn wildcard_subdomains() {
+        let pattern = Pattern::parse("https://*.pxeger.com");
+        assert!(pattern.matches("https://ato.pxeger.com"));
+        assert!(pattern.matches("https://a.b.pxeger.com"));
+        assert!(!pattern.matches("https://evilpxeger.com"));
+        assert!(!pattern.matches("https://ato.pxeger.com.evil.com"));
+        assert!(!pattern.matches("http://ato.pxeger.com"));
+        // the port is part of the pattern, so it can't be smuggled into the subdomain
+        assert!(!pattern.matches("https://ato.pxeger.com:8443"));
+        assert!(!pattern.matches("https://evil.com:1.pxeger.com"));
+    }
+
+    #[test]
+    fn wildcard_with_port() {
+        let pattern = Pattern::parse("http://*.localhost:3000");
+        assert!(pattern.matches("http://ato.localhost:3000"));
+        assert!(!pattern.matches("http://localhost:3000"));
+        assert!(!pattern.matches("http://ato.localhost:3001"));
+    }
+
+    #[test]
+    fn star_without_dot_is_literal() {
+        let pattern = Pattern::parse("https://*pxeger.com");
+        assert!(!pattern.matches("https://evilpxeger.com"));
+        assert!(pattern.matches("https://*pxeger.com"));
+    }
+}
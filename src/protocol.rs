@@ -0,0 +1,121 @@
+//! The messages sent between clients and the server over the websocket (see docs/api.md).
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub language: String,
+    pub code: ByteBuf,
+    #[serde(default /* = None */)]
+    pub custom_runner: Option<ByteBuf>,
+    pub input: ByteBuf,
+    pub arguments: Vec<ByteBuf>,
+    pub options: Vec<ByteBuf>,
+    /// in seconds (if None, the language's default)
+    #[serde(
+        default, /* = None */
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<i32>,
+    /// memory limit in MiB (if None, the language's default)
+    #[serde(
+        default, /* = None */
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub memory: Option<u64>,
+    /// maximum number of processes and threads (if None, the language's default)
+    #[serde(
+        default, /* = None */
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pids: Option<u64>,
+    /// whether the runner can switch to an unprivileged user (if None, the language decides)
+    #[serde(
+        default, /* = None */
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub unprivileged_user: Option<bool>,
+    /// whether to hide the time and other sources of variation from the program, as far as possible
+    #[serde(default)]
+    pub deterministic: bool,
+    /// which of the language's versions to run (if None, the language's default)
+    #[serde(
+        default, /* = None */
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub version: Option<String>,
+}
+
+/// lets fields be left out, without accepting nil for them
+fn deserialize_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StreamResponse {
+    Queued {
+        position: usize,
+    },
+    Stdout(ByteBuf),
+    Stderr(ByteBuf),
+    /// something the client should know about its request, which doesn't stop it from running
+    Warning(String),
+    Done {
+        timed_out: bool,
+        stdout_truncated: bool,
+        stderr_truncated: bool,
+        status_type: String,
+        status_value: i32,
+        real: i64,
+        kernel: i64,
+        user: i64,
+        max_mem: i64,
+        waits: i64,
+        preemptions: i64,
+        major_page_faults: i64,
+        minor_page_faults: i64,
+        input_ops: i64,
+        output_ops: i64,
+        blocked_syscalls: Vec<String>,
+        /// the version of the language which ran, if it names its versions
+        version: Option<String>,
+        /// the exact version of the compiler or interpreter, if it has been detected
+        detected_version: Option<String>,
+    },
+}
+
+impl StreamResponse {
+    /// For a Done message, the status a shell would give the program: its exit code, or 128 plus
+    /// the number of the signal which killed it.
+    pub fn exit_status(&self) -> Option<u8> {
+        let StreamResponse::Done {
+            status_type,
+            status_value,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let value = u8::try_from(*status_value).ok();
+        match status_type.as_str() {
+            "exited" => value,
+            "killed" | "core_dumped" => value.and_then(|signal| signal.checked_add(128)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlMessage {
+    Kill,
+}
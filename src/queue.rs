@@ -0,0 +1,135 @@
+use crate::shared;
+use nix::unistd::getpid;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering::SeqCst};
+
+/// maximum number of sandboxes which can be running at once, whatever the configured limit
+const MAX_SLOTS: usize = 1024;
+
+/// Server-wide record of which sandboxes are running, which lives in shared memory so that it also
+/// covers old versions of the server which are still finishing their requests after an upgrade.
+///
+/// Requests which are waiting for a slot are queued by each process's event loop (see server.rs).
+pub struct State {
+    max_running: AtomicUsize,
+    // each slot is either 0, meaning it is free, or the pid of the server process running a sandbox
+    // in it
+    slots: [AtomicI32; MAX_SLOTS],
+}
+
+fn get_max_running() -> usize {
+    let max_running = match std::env::var("ATO_MAX_SANDBOXES") {
+        Ok(s) => s.parse().expect("$ATO_MAX_SANDBOXES is not a valid number"),
+        Err(std::env::VarError::NotUnicode(_)) => panic!("$ATO_MAX_SANDBOXES is invalid Unicode"),
+        Err(std::env::VarError::NotPresent) => {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        }
+    };
+    if max_running == 0 {
+        panic!("$ATO_MAX_SANDBOXES must be at least 1");
+    }
+    max_running
+}
+
+/// Read the concurrency limit into shared memory. Must be called after shared::init.
+pub fn init() {
+    shared::get()
+        .queue
+        .max_running
+        .store(get_max_running(), SeqCst);
+}
+
+/// The number of sandboxes which can be running at once.
+pub fn max_running() -> usize {
+    shared::get().queue.max_running.load(SeqCst)
+}
+
+/// A claim on the right to run a sandbox, which is released when dropped.
+pub struct Slot {
+    slot: &'static AtomicI32,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slot.store(0, SeqCst);
+    }
+}
+
+/// Claim the right to run a sandbox, if there is capacity for another one.
+pub fn try_start() -> Option<Slot> {
+    start_in(&shared::get().queue)
+}
+
+fn start_in(state: &'static State) -> Option<Slot> {
+    let pid = getpid().as_raw();
+    // claim a slot before counting, so that two processes can't both take the last one
+    let slot = state
+        .slots
+        .iter()
+        .find(|slot| slot.compare_exchange(0, pid, SeqCst, SeqCst).is_ok())?;
+    // RAII ensures the slot is released if we return early
+    let slot = Slot { slot };
+    let mut running = 0;
+    for slot in &state.slots {
+        let owner = slot.load(SeqCst);
+        if owner == 0 {
+            continue;
+        }
+        if shared::is_alive(owner) {
+            running += 1;
+        } else {
+            // the process holding this slot died without releasing it (e.g. it was SIGKILLed), so
+            // reclaim it. If this fails, someone else has already done so
+            let _ = slot.compare_exchange(owner, 0, SeqCst, SeqCst);
+        }
+    }
+    // running includes the slot we just claimed
+    (running <= state.max_running.load(SeqCst)).then_some(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::tests::dead_pid;
+
+    fn state(max_running: usize) -> &'static State {
+        // this is safe because all-zero is a valid State (see shared.rs)
+        let state: &'static State = Box::leak(Box::new(unsafe { std::mem::zeroed() }));
+        state.max_running.store(max_running, SeqCst);
+        state
+    }
+
+    fn claimed(state: &State) -> usize {
+        state.slots.iter().filter(|s| s.load(SeqCst) != 0).count()
+    }
+
+    #[test]
+    fn capped() {
+        let state = state(2);
+        let first = start_in(state).unwrap();
+        let second = start_in(state).unwrap();
+        assert!(start_in(state).is_none());
+        // the failed attempt doesn't keep its slot
+        assert_eq!(claimed(state), 2);
+        drop(first);
+        let third = start_in(state).unwrap();
+        assert!(start_in(state).is_none());
+        drop(second);
+        drop(third);
+        assert_eq!(claimed(state), 0);
+    }
+
+    #[test]
+    fn slots_of_dead_processes_reclaimed() {
+        let state = state(1);
+        state.slots[0].store(dead_pid(), SeqCst);
+        state.slots[7].store(dead_pid(), SeqCst);
+        let slot = start_in(state).unwrap();
+        assert_eq!(state.slots[0].load(SeqCst), 0);
+        assert_eq!(state.slots[1].load(SeqCst), getpid().as_raw());
+        assert_eq!(claimed(state), 1);
+        drop(slot);
+        // but a live process's slots still count
+        state.slots[3].store(std::process::id() as i32, SeqCst);
+        assert!(start_in(state).is_none());
+    }
+}
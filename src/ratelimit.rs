@@ -0,0 +1,210 @@
+use crate::{Error, client::Client, shared};
+use nix::time::{ClockId, clock_gettime};
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+/// number of clients whose rate limits can be tracked at once. When the table is full, clients which
+/// haven't made any requests recently are forgotten to make space
+const TABLE_SIZE: usize = 4096;
+
+/// Token buckets for each client, which live in shared memory.
+pub struct State {
+    buckets: shared::Table<Bucket, TABLE_SIZE>,
+}
+
+struct Bucket {
+    // number of tokens taken out of the bucket and not refilled yet, as of `updated`, so 0 means full.
+    // (counting them this way round means an all-zero Bucket is a valid new one)
+    used: f64,
+    // time of the last update, in milliseconds
+    updated: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    /// tokens added back to each bucket per minute
+    per_minute: f64,
+    /// size of each bucket, i.e. the maximum number of requests which can be made in quick succession
+    burst: f64,
+}
+
+fn get_env_number(name: &str) -> Option<f64> {
+    match std::env::var(name) {
+        Ok(s) => {
+            let n: f64 = s
+                .parse()
+                .unwrap_or_else(|_| panic!("${name} is not a valid number"));
+            if n.is_finite() && n > 0.0 {
+                Some(n)
+            } else {
+                panic!("${name} must be positive")
+            }
+        }
+        Err(std::env::VarError::NotUnicode(_)) => panic!("${name} is invalid Unicode"),
+        Err(std::env::VarError::NotPresent) => None,
+    }
+}
+
+fn get_limit() -> Option<Limit> {
+    // rate limiting is disabled unless configured
+    let per_minute = get_env_number("ATO_RATE_LIMIT_PER_MINUTE")?;
+    let burst = get_env_number("ATO_RATE_LIMIT_BURST").unwrap_or(per_minute);
+    if burst < 1.0 {
+        panic!("$ATO_RATE_LIMIT_BURST must be at least 1");
+    }
+    Some(Limit { per_minute, burst })
+}
+
+pub static LIMIT: LazyLock<Option<Limit>> = LazyLock::new(get_limit);
+
+fn now() -> u64 {
+    // CLOCK_MONOTONIC is the same across all processes
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("error reading monotonic clock");
+    now.tv_sec() as u64 * 1000 + now.tv_nsec() as u64 / 1_000_000
+}
+
+impl Limit {
+    fn per_ms(&self) -> f64 {
+        self.per_minute / 60_000.0
+    }
+}
+
+impl Bucket {
+    /// number of tokens which would be used after refilling up to `now`
+    fn used_at(&self, limit: &Limit, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        (self.used - elapsed * limit.per_ms()).max(0.0)
+    }
+
+    /// Take `cost` tokens at `now`, if there is at least one token left. If there isn't, return the
+    /// number of seconds until there will be.
+    fn take(&mut self, limit: &Limit, now: u64, cost: f64) -> Result<(), u64> {
+        let used = self.used_at(limit, now);
+        if used + 1.0 > limit.burst {
+            let wait = (used + 1.0 - limit.burst) / limit.per_ms() / 1000.0;
+            return Err(wait.ceil() as u64);
+        }
+        self.used = used + cost;
+        self.updated = now;
+        Ok(())
+    }
+}
+
+/// Take `cost` tokens from the client's bucket, if there is at least one token left in it.
+/// If there isn't, return the number of seconds until there will be.
+fn take(client: &Client, cost: f64) -> Result<(), u64> {
+    // clients using API keys are limited per key, rather than per address
+    let (key, limit) = match client.api_key {
+        Some(api_key) => (
+            shared::hash_key(("api key", &api_key.token)),
+            api_key.rate_limit.as_ref().or(LIMIT.as_ref()),
+        ),
+        None => (
+            shared::hash_key(("address", client.address)),
+            LIMIT.as_ref(),
+        ),
+    };
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let now = now();
+    let buckets = &shared::get().rate_limits.buckets;
+    let result = buckets.with(
+        key,
+        |bucket| bucket.used_at(limit, now) == 0.0,
+        |bucket| bucket.take(limit, now, cost),
+    );
+    // if the table is full of active clients, let the request through rather than punishing
+    // everyone for the behaviour of a few
+    result.unwrap_or(Ok(()))
+}
+
+/// Check whether the client would be allowed to make a request, without counting it. Returns the
+/// number of seconds to wait if not.
+pub fn check(client: &Client) -> Result<(), u64> {
+    take(client, 0.0)
+}
+
+/// Count a request towards the client's rate limit, failing if it has been exceeded.
+pub fn consume(client: &Client) -> Result<(), Error> {
+    take(client, 1.0).map_err(|wait| {
+        Error::TryAgainLater(format!("rate limit exceeded; try again in {wait} seconds"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_PER_SECOND: Limit = Limit {
+        per_minute: 60.0,
+        burst: 3.0,
+    };
+
+    fn new_bucket(now: u64) -> Bucket {
+        Bucket {
+            used: 0.0,
+            updated: now,
+        }
+    }
+
+    #[test]
+    fn burst_then_overflow() {
+        let mut bucket = new_bucket(1000);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(&ONE_PER_SECOND, 1000, 1.0), Ok(()));
+        }
+        // one token a second, and the bucket is empty
+        assert_eq!(bucket.take(&ONE_PER_SECOND, 1000, 1.0), Err(1));
+        // failed attempts aren't counted
+        assert_eq!(bucket.used, 3.0);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = new_bucket(0);
+        for _ in 0..3 {
+            bucket.take(&ONE_PER_SECOND, 0, 1.0).unwrap();
+        }
+        assert_eq!(bucket.take(&ONE_PER_SECOND, 500, 1.0), Err(1));
+        assert_eq!(bucket.take(&ONE_PER_SECOND, 1000, 1.0), Ok(()));
+        assert_eq!(bucket.take(&ONE_PER_SECOND, 1000, 1.0), Err(1));
+        // a long wait refills it, but no further than full
+        assert_eq!(bucket.used_at(&ONE_PER_SECOND, 60_000), 0.0);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(&ONE_PER_SECOND, 60_000, 1.0), Ok(()));
+        }
+        assert_eq!(bucket.take(&ONE_PER_SECOND, 60_000, 1.0), Err(1));
+    }
+
+    #[test]
+    fn wait_is_rounded_up() {
+        let slow = Limit {
+            per_minute: 1.0,
+            burst: 1.0,
+        };
+        let mut bucket = new_bucket(0);
+        bucket.take(&slow, 0, 1.0).unwrap();
+        assert_eq!(bucket.take(&slow, 0, 1.0), Err(60));
+        assert_eq!(bucket.take(&slow, 59_500, 1.0), Err(1));
+        assert_eq!(bucket.take(&slow, 60_000, 1.0), Ok(()));
+    }
+
+    #[test]
+    fn checking_takes_nothing() {
+        let mut bucket = new_bucket(0);
+        for _ in 0..10 {
+            assert_eq!(bucket.take(&ONE_PER_SECOND, 0, 0.0), Ok(()));
+        }
+        assert_eq!(bucket.used, 0.0);
+    }
+
+    #[test]
+    fn clock_going_backwards() {
+        let mut bucket = new_bucket(5000);
+        bucket.take(&ONE_PER_SECOND, 5000, 1.0).unwrap();
+        // treated as no time having passed
+        assert_eq!(bucket.used_at(&ONE_PER_SECOND, 4000), 1.0);
+    }
+}
@@ -1,34 +1,47 @@
 use crate::constants::*;
+use crate::egress;
+use crate::ids::Ids;
 use crate::languages::*;
-use crate::network::setup_network;
-use crate::{Connection, ControlMessage, Error, Request, StreamResponse, check};
+use crate::network::{setup_network, setup_network_files};
+use crate::seccomp;
+use crate::versions;
+use crate::zygote::{self, Job, Stopped};
+use crate::{ControlMessage, Error, Limits, Request, StreamResponse, check};
 
 use capctl::{caps, prctl};
 use clone3::Clone3;
 use close_fds::close_open_fds;
 use hex::ToHex;
+use landlock::{
+    ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, path_beneath_rules,
+};
 use nix::{
+    errno::Errno,
     fcntl::{FcntlArg, OFlag, fcntl},
+    libc,
     mount::{MsFlags, mount},
     poll::{PollFd, PollFlags, poll},
     sys::{
         eventfd::{EfdFlags, eventfd},
-        resource::{Resource, UsageWho::RUSAGE_CHILDREN, getrusage, setrlimit},
+        resource::{Resource, setrlimit},
         stat::Mode,
-        time::TimeValLike,
-        wait::{self, WaitPidFlag, WaitStatus::*, waitid},
+        statvfs::{FsFlags, statvfs},
+        wait::{WaitStatus, WaitStatus::*},
     },
+    time::{ClockId, clock_gettime},
     unistd::{
-        Gid, Uid, chdir, close, dup2, execve, mkdir, pipe, pivot_root, read, setresgid, setresuid,
-        symlinkat,
+        Gid, Pid, Uid, chdir, close, dup2, execve, mkdir, pipe, pivot_root, read, setresgid,
+        setresuid, symlinkat, write,
     },
 };
 use rand::Rng;
 use serde_bytes::ByteBuf;
 use std::ffi::{CStr, CString};
 use std::fs::File;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
 
 /// log `Err`s to stderr but don't stop execution
 macro_rules! check_continue {
@@ -44,6 +57,19 @@ macro_rules! check_continue {
     }
 }
 
+/// Whether to describe each step of setting up the sandbox on stderr (see local.rs). It must be set
+/// before the zygote is started, so that the zygote has it too.
+pub static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// log a step of setting up the sandbox to stderr, if TRACE is set
+macro_rules! trace {
+    ($($x:expr),* $(,)?) => {
+        if TRACE.load(Relaxed) {
+            eprintln!("trace: {}", format!($($x,)*));
+        }
+    };
+}
+
 /// convert a string literal into a C string object
 macro_rules! cstr {
     ($x:literal) => {
@@ -54,41 +80,59 @@ macro_rules! cstr {
 const STDOUT_FD: std::os::unix::io::RawFd = 1;
 const STDERR_FD: std::os::unix::io::RawFd = 2;
 
-struct Cgroup<'a> {
-    cgroup: &'a PathBuf,
+const CGROUP_REMOVE_MAX_ATTEMPT_TIME: u128 = 100; // ms
+
+struct Cgroup {
+    cgroup: PathBuf,
+    // whether it has been removed, or given up on
+    removed: bool,
+}
+
+impl Cgroup {
+    fn kill(&self) -> std::io::Result<()> {
+        std::fs::write(self.cgroup.join("cgroup.kill"), "1")
+    }
+
+    /// Try once to remove the cgroup, which fails while its processes are still dying. Returns
+    /// false if it should be tried again.
+    fn try_remove(&mut self) -> bool {
+        // note: even though the cgroup directory is not empty, the kernel wants us to just use rmdir on it
+        // (because we can't remove any of the special files inside individually)
+        match std::fs::remove_dir(&self.cgroup) {
+            // cgroup not finished dying yet: retry
+            Err(e) if e.kind() == std::io::ErrorKind::ResourceBusy => return false,
+            Err(e) => eprintln!("error removing cgroup: {e}"),
+            Ok(()) => (),
+        }
+        self.removed = true;
+        true
+    }
 }
 
-impl Drop for Cgroup<'_> {
+impl Drop for Cgroup {
     fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
         // clean up this cgroup by killing all its processes and then removing it
-        if let Err(e) = std::fs::write(self.cgroup.join("cgroup.kill"), "1") {
+        if let Err(e) = self.kill() {
             eprintln!("error killing cgroup: {e}");
             return;
         }
 
-        const CGROUP_REMOVE_MAX_ATTEMPT_TIME: u128 = 100; // ms
-
         let timer = std::time::Instant::now();
         let mut attempt_counter = 0;
-        // note: even though the cgroup directory is not empty, the kernel wants us to just use rmdir on it
-        // (because we can't remove any of the special files inside individually)
-        while let Err(e) = std::fs::remove_dir(&self.cgroup) {
-            if e.kind() == std::io::ErrorKind::ResourceBusy {
-                // cgroup not finished dying yet: retry?
-                let elapsed = timer.elapsed().as_millis();
-                if elapsed < CGROUP_REMOVE_MAX_ATTEMPT_TIME {
-                    attempt_counter += 1;
-                    std::thread::yield_now();
-                    continue;
-                } else {
-                    eprintln!(
-                        "giving up removing cgroup after {elapsed}ms and {attempt_counter} attempts"
-                    );
-                }
+        while !self.try_remove() {
+            let elapsed = timer.elapsed().as_millis();
+            if elapsed < CGROUP_REMOVE_MAX_ATTEMPT_TIME {
+                attempt_counter += 1;
+                std::thread::yield_now();
             } else {
-                eprintln!("error removing cgroup: {e}");
+                eprintln!(
+                    "giving up removing cgroup after {elapsed}ms and {attempt_counter} attempts"
+                );
+                break;
             }
-            break;
         }
     }
 }
@@ -110,11 +154,15 @@ fn create_cgroup() -> Result<PathBuf, Error> {
     Ok(path)
 }
 
-fn setup_cgroup(path: &PathBuf) -> Result<(), Error> {
+fn setup_cgroup(path: &PathBuf, limits: &Limits) -> Result<(), Error> {
     // this sets some resource limits, but the others are set with ordinary POSIX rlimits:
     // see the set_resource_limits function
     // const MEMORY_HIGH: u64 = 512 * MiB;
     // check!(std::fs::write(path.join("memory.high"), MEMORY_HIGH.to_string()), "error writing cgroup memory.high: {}");
+    check!(
+        std::fs::write(path.join("memory.max"), limits.memory.to_string()),
+        "error writing cgroup memory.max: {}"
+    );
     // disable swap
     check!(
         std::fs::write(path.join("memory.swap.max"), "0"),
@@ -131,25 +179,61 @@ fn random_id() -> String {
         .encode_hex::<String>()
 }
 
-pub fn invoke(
-    request: &Request,
-    language: &Language,
-    connection: &mut Connection,
-    connection_fd: i32,
-) -> Result<(), Error> {
-    let cgroup = create_cgroup()?;
-    let cgroup_cleanup = Cgroup { cgroup: &cgroup };
-    setup_cgroup(&cgroup)?;
+/// A sandbox created by the zygote, from the zygote's point of view.
+pub struct Sandbox {
+    pid: i32,
+    pub pidfd: i32,
+    pub stdout_r: i32,
+    pub stderr_r: i32,
+    /// receives the egress proxy's listener, if the sandbox has one
+    pub egress_r: Option<OwnedFd>,
+    pub seccomp: seccomp::Monitor,
+    cgroup: Cgroup,
+    // released after the cgroup is removed, once nothing is running as them any more
+    ids: Ids,
+}
+
+/// Create a sandbox running the request. Must only be called from a single-threaded process, i.e.
+/// the zygote.
+pub fn spawn(request: &Request, language: &Language, limits: &Limits) -> Result<Sandbox, Error> {
+    let Some(version) = language.version(&request.language, request.version.as_deref()) else {
+        return Err(Error::PolicyViolation("no such version".to_string()));
+    };
+    let ids = Ids::allocate()?;
+    if unprivileged_user(request, language) && ids.unprivileged.is_none() {
+        return Err(Error::PolicyViolation(
+            "this server can't run programs as an unprivileged user".to_string(),
+        ));
+    }
+    let cgroup = Cgroup {
+        cgroup: create_cgroup()?,
+        removed: false,
+    };
+    setup_cgroup(&cgroup.cgroup, limits)?;
     let cgroup_fd = check!(
-        nix::fcntl::open(&cgroup, OFlag::O_DIRECTORY | OFlag::O_PATH, Mode::empty()),
+        nix::fcntl::open(
+            &cgroup.cgroup,
+            OFlag::O_DIRECTORY | OFlag::O_PATH,
+            Mode::empty()
+        ),
         "error opening cgroup dir: {}",
     );
 
     let (stdout_r, stdout_w) = check!(pipe(), "error creating stdout pipe: {}");
     let (stderr_r, stderr_w) = check!(pipe(), "error creating stderr pipe: {}");
-
-    let uid = Uid::current();
-    let gid = Gid::current();
+    let (seccomp_r, seccomp_w) = check!(
+        zygote::seqpacket_pair(),
+        "error creating seccomp socket: {}"
+    );
+    // the child waits on this until its ID mappings have been written
+    let (ids_r, ids_w) = check!(pipe(), "error creating ID mapping pipe: {}");
+    let (egress_r, egress_w) = if limits.egress.is_some() {
+        let (egress_r, egress_w) =
+            check!(zygote::seqpacket_pair(), "error creating egress socket: {}");
+        (Some(egress_r), Some(egress_w))
+    } else {
+        (None, None)
+    };
 
     let mut pidfd = -1;
     let mut clone3 = Clone3::default();
@@ -164,19 +248,32 @@ pub fn invoke(
         .flag_newuts()
         .flag_pidfd(&mut pidfd)
         .flag_into_cgroup(&cgroup_fd);
-    let timer = std::time::Instant::now();
-    // this is safe because we haven't used more than one thread so far in this program
-    if check!(unsafe { clone3.call() }, "error clone3ing main child: {}") == 0 {
+    // this is safe because the zygote only has one thread
+    let pid = check!(unsafe { clone3.call() }, "error clone3ing main child: {}");
+    if pid == 0 {
         // in child
         // avoid suicide
-        std::mem::forget(cgroup_cleanup);
+        std::mem::forget(cgroup);
 
         // close unused pipe ends to ensure proper synchronisation
         // TODO: do we need to explicitly close these read pipe ends, given the close_range call below?
         check_continue!(close(stdout_r), "error closing stdout read end: {}");
         check_continue!(close(stderr_r), "error closing stderr read end: {}");
-
-        run_child(&request, &language, stdout_w, stderr_w, uid, gid);
+        check_continue!(close(ids_w), "error closing ID mapping pipe write end: {}");
+
+        drop(seccomp_r);
+        drop(egress_r);
+        run_child(
+            request,
+            language,
+            &version,
+            limits,
+            stdout_w,
+            stderr_w,
+            seccomp_w.as_raw_fd(),
+            ids_r,
+            egress_w.as_ref().map(|fd| fd.as_raw_fd()),
+        );
         // run_child should never return if successful, so we exit assuming failure
         std::process::exit(2);
     } else {
@@ -184,26 +281,162 @@ pub fn invoke(
         // close unused pipe ends
         check!(close(stdout_w), "error closing stdout write end: {}");
         check!(close(stderr_w), "error closing stderr write end: {}");
-
-        run_parent(
+        check!(close(cgroup_fd), "error closing cgroup dir: {}");
+        check!(close(ids_r), "error closing ID mapping pipe read end: {}");
+        drop(seccomp_w);
+        drop(egress_w);
+        let sandbox = Sandbox {
+            pid,
+            pidfd,
             stdout_r,
             stderr_r,
-            pidfd,
-            cgroup_cleanup,
-            timer,
-            request.timeout,
-            connection,
-            connection_fd,
-        )
+            egress_r,
+            seccomp: seccomp::Monitor::new(seccomp_r),
+            cgroup,
+            ids,
+        };
+        let result =
+            map_ids(pid, &sandbox.ids, unprivileged_user(request, language)).and_then(|()| {
+                check!(write(ids_w, &[0]), "error writing to ID mapping pipe: {}");
+                Ok(())
+            });
+        check!(close(ids_w), "error closing ID mapping pipe write end: {}");
+        if let Err(e) = result {
+            // the child gives up when the pipe is closed without anything written to it
+            for fd in [sandbox.stdout_r, sandbox.stderr_r] {
+                let _ = close(fd);
+            }
+            let _ = sandbox.stop();
+            return Err(e);
+        }
+        Ok(sandbox)
+    }
+}
+
+impl Sandbox {
+    /// Kill everything left in the sandbox. Its main process has exited once `pidfd` is readable,
+    /// and then `finish` can be called without blocking.
+    pub fn kill(&self) -> Result<(), Error> {
+        check!(self.cgroup.kill(), "error killing cgroup: {}");
+        Ok(())
+    }
+
+    /// Kill everything left in the sandbox, waiting for its main process to exit, and clean it up.
+    pub fn stop(self) -> Result<Stopped, Error> {
+        self.kill()?;
+        // dropping the leftovers removes the cgroup, retrying until it has finished dying
+        self.finish().map(|(stopped, _)| stopped)
+    }
+
+    /// Collect the exit status and resource usage of the sandbox's main process, once it has
+    /// exited. The cgroup still has to be removed afterwards (see Leftovers).
+    pub fn finish(self) -> Result<(Stopped, Leftovers), Error> {
+        let mut wait_status = 0;
+        // this is safe because an all-zero rusage is valid
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        // wait4 is used rather than getrusage(RUSAGE_CHILDREN), because the zygote has lots of
+        // children, and we only want this one's usage
+        check!(
+            // this is safe because the pointers are to valid locals
+            Errno::result(unsafe {
+                libc::wait4(self.pid, &mut wait_status, libc::__WALL, &mut usage)
+            }),
+            "error getting wait4 result: {}"
+        );
+        let _ = close(self.pidfd);
+        let stopped = Stopped {
+            wait_status,
+            usage: usage.into(),
+            blocked_syscalls: self.seccomp.blocked.into_iter().collect(),
+        };
+        let leftovers = Leftovers {
+            cgroup: self.cgroup,
+            _ids: self.ids,
+            since: std::time::Instant::now(),
+        };
+        Ok((stopped, leftovers))
+    }
+}
+
+/// The parts of a stopped sandbox which can't be cleaned up until the kernel has finished with its
+/// processes.
+pub struct Leftovers {
+    cgroup: Cgroup,
+    // released after the cgroup is removed, once nothing is running as them any more
+    _ids: Ids,
+    since: std::time::Instant,
+}
+
+impl Leftovers {
+    /// Try to remove the cgroup, without waiting. Returns false if it should be tried again later,
+    /// after a short while.
+    pub fn clean_up(&mut self) -> bool {
+        if self.cgroup.try_remove() {
+            return true;
+        }
+        let elapsed = self.since.elapsed().as_millis();
+        if elapsed < CGROUP_REMOVE_MAX_ATTEMPT_TIME {
+            return false;
+        }
+        eprintln!("giving up removing cgroup after {elapsed}ms");
+        self.cgroup.removed = true;
+        true
     }
 }
 
-fn wait_child(
-    pidfd: i32,
-    connection: Arc<Mutex<&mut Connection>>,
-    connection_fd: i32,
-    timeout: i32,
-) -> Result<bool, Error> {
+/// Where the messages about a running program go: its output as it comes, and then a Done message.
+/// The server sends them to the client through the event loop (see worker.rs), and the `run`
+/// subcommand writes them to the terminal (see local.rs).
+pub trait OutputSink: Send {
+    fn output(&mut self, message: StreamResponse) -> Result<(), Error>;
+}
+
+/// Where control messages for a running program come from.
+pub trait ControlSource {
+    /// A file descriptor which becomes readable when there is a control message, or hangs up when
+    /// the program should be killed because nobody wants its output any more. If None, there are
+    /// never any control messages.
+    fn fd(&self) -> Option<RawFd>;
+
+    /// Read a control message, once the file descriptor is readable.
+    fn read(&mut self) -> Result<ControlMessage, Error>;
+}
+
+/// A ControlSource with no control messages, so the program runs until it finishes or times out.
+pub struct NoControl;
+
+impl ControlSource for NoControl {
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn read(&mut self) -> Result<ControlMessage, Error> {
+        Err(Error::InternalError("there are no control messages".into()))
+    }
+}
+
+/// Run the request in a sandbox. Must be called after zygote::start.
+pub fn invoke(
+    request: &Request,
+    limits: &Limits,
+    output: &mut dyn OutputSink,
+    control: &mut dyn ControlSource,
+) -> Result<(), Error> {
+    let timer = std::time::Instant::now();
+    let job = zygote::spawn(request, limits)?;
+    let detected_version = versions::get(&request.language, request.version.as_deref());
+    run_parent(
+        &job,
+        timer,
+        request.version.clone(),
+        detected_version,
+        limits,
+        output,
+        control,
+    )
+}
+
+fn wait_child(pidfd: i32, control: &mut dyn ControlSource, timeout: i32) -> Result<bool, Error> {
     // use a poll to wait for either:
     // - timeout to expire
     // - child to exit
@@ -211,7 +444,8 @@ fn wait_child(
     let mut poll_args = [
         // pidfd fires a POLLIN event when the process finishes
         PollFd::new(pidfd, PollFlags::POLLIN),
-        PollFd::new(connection_fd, PollFlags::POLLIN),
+        // poll ignores negative file descriptors
+        PollFd::new(control.fd().unwrap_or(-1), PollFlags::POLLIN),
     ];
     let poll_result = check!(
         poll(&mut poll_args, timeout * 1000 /* ms */),
@@ -237,7 +471,7 @@ fn wait_child(
         if stdin_events.contains(PollFlags::POLLIN) {
             // received control message via stdin
             use ControlMessage::*;
-            match connection.lock().unwrap().read_message()? {
+            match control.read()? {
                 Kill => {
                     // continue to drop (i.e. kill), and set timed_out = false
                     Ok(false)
@@ -276,33 +510,39 @@ impl Drop for QuitEventFd {
 }
 
 fn run_parent(
-    stdout_r: i32,
-    stderr_r: i32,
-    pidfd: i32,
-    cgroup_cleanup: Cgroup,
+    job: &Job,
     timer: std::time::Instant,
-    timeout: i32,
-    connection: &mut Connection,
-    connection_fd: i32,
+    version: Option<String>,
+    detected_version: Option<String>,
+    limits: &Limits,
+    output: &mut dyn OutputSink,
+    control: &mut dyn ControlSource,
 ) -> Result<(), Error> {
-    let (timed_out, connection, [stdout_truncated, stderr_truncated]) =
-        std::thread::scope(move |threads| {
-            let connection = Arc::new(Mutex::new(connection));
-            // there has to be a better way of doing this
-            let connection2 = connection.clone();
+    let output = Mutex::new(output);
+    let (timed_out, stopped, [stdout_truncated, stderr_truncated]) =
+        std::thread::scope(|threads| {
+            let output = &output;
 
             // RAII ensures that the quit eventfd is triggered when it's dropped, so that the
             // output_handler doesn't get confused if the main thread encounters an error
             let quit = QuitEventFd::new()?;
 
-            let output_handler =
-                threads.spawn(move || handle_output(stdout_r, stderr_r, quit.fd, connection2));
+            let output_handler = threads.spawn(move || {
+                handle_output(job.stdout_r, job.stderr_r, quit.fd, output, limits.output)
+            });
+
+            let egress_proxy = match (limits.egress, job.egress_r) {
+                (Some(policy), Some(egress_r)) => {
+                    Some(threads.spawn(move || egress::serve(policy, egress_r, quit.fd)))
+                }
+                _ => None,
+            };
 
             // wait for child
-            let timed_out = wait_child(pidfd, connection.clone(), connection_fd, timeout)?;
+            let timed_out = wait_child(job.pidfd, control, limits.timeout)?;
 
             // kill process
-            drop(cgroup_cleanup);
+            let stopped = job.stop()?;
 
             // tell output_handler to quit
             drop(quit);
@@ -314,24 +554,20 @@ fn run_parent(
                 Ok(Ok(truncateds)) => truncateds,
                 Ok(Err(e)) => return Err(e),
             };
+            if let Some(egress_proxy) = egress_proxy {
+                match egress_proxy.join() {
+                    Err(panic) => std::panic::panic_any(panic),
+                    Ok(result) => result?,
+                }
+            }
 
-            let connection = Arc::try_unwrap(connection)
-                .unwrap_or_else(|_| panic!("excess references to Arc<Connection>"))
-                .into_inner()
-                .unwrap();
-            Ok((timed_out, connection, truncateds))
+            Ok((timed_out, stopped, truncateds))
         })?;
 
-    // TODO: investigate why this reports ECHILD if the child errors and __WALL is not provided
-    // something to do with the fact we use a second thread above which is a different kind of child process?
-    // or the rust threading runtime doing strange things to signal handlers?
-    // https://github.com/torvalds/linux/blob/a63f2e7cb1107ab124f80407e5eb8579c04eb7a9/kernel/exit.c#L968
+    // the pid doesn't matter, because it's only used to fill in the WaitStatus
     let wait_result = check!(
-        waitid(
-            wait::Id::PIDFd(pidfd),
-            WaitPidFlag::WEXITED | WaitPidFlag::__WALL
-        ),
-        "error getting waitid result: {}"
+        WaitStatus::from_raw(Pid::from_raw(0), stopped.wait_status),
+        "error decoding wait status: {}"
     );
 
     let (status_type, status_value) = match wait_result {
@@ -344,27 +580,26 @@ fn run_parent(
         }
     };
 
-    let stats = check!(
-        getrusage(RUSAGE_CHILDREN),
-        "error getting resource usage: {}"
-    );
-
-    connection.output_message(StreamResponse::Done {
+    let stats = stopped.usage;
+    output.into_inner().unwrap().output(StreamResponse::Done {
         timed_out,
-        status_type,
+        status_type: status_type.to_string(),
         status_value,
         stdout_truncated,
         stderr_truncated,
         real: timer.elapsed().as_nanos() as i64,
-        kernel: stats.system_time().num_nanoseconds(),
-        user: stats.user_time().num_nanoseconds(),
-        max_mem: stats.max_rss(),
-        waits: stats.voluntary_context_switches(),
-        preemptions: stats.involuntary_context_switches(),
-        major_page_faults: stats.major_page_faults(),
-        minor_page_faults: stats.minor_page_faults(),
-        input_ops: stats.block_reads(),
-        output_ops: stats.block_writes(),
+        kernel: stats.kernel,
+        user: stats.user,
+        max_mem: stats.max_mem,
+        waits: stats.waits,
+        preemptions: stats.preemptions,
+        major_page_faults: stats.major_page_faults,
+        minor_page_faults: stats.minor_page_faults,
+        input_ops: stats.input_ops,
+        output_ops: stats.output_ops,
+        blocked_syscalls: stopped.blocked_syscalls,
+        version,
+        detected_version,
     })?;
     Ok(())
 }
@@ -373,7 +608,8 @@ fn handle_output(
     stdout_r: i32,
     stderr_r: i32,
     quit: i32,
-    connection: Arc<Mutex<&mut Connection>>,
+    output: &Mutex<&mut dyn OutputSink>,
+    max_output: usize,
 ) -> Result<[bool; 2], Error> {
     for (name, pipe) in [("stdout", stdout_r), ("stderr", stderr_r)] {
         check!(
@@ -384,7 +620,6 @@ fn handle_output(
     }
 
     const OUTPUT_BUF_SIZE: usize = 16 * KiB as usize;
-    const MAX_SENSIBLE_OUTPUT_SIZE: usize = 128 * KiB as usize;
     let mut totals = [0usize; 2];
     let mut open = [true; 2];
     let mut truncated = [false; 2];
@@ -420,7 +655,7 @@ fn handle_output(
                 let mut buf = [0u8; OUTPUT_BUF_SIZE];
                 let len = check!(read(pipe, &mut buf), "error reading from {name}: {}");
                 totals[i] += len;
-                if totals[i] > MAX_SENSIBLE_OUTPUT_SIZE {
+                if totals[i] > max_output {
                     check!(
                         close(pipe),
                         "error closing {name} (after too much output): {}"
@@ -429,7 +664,7 @@ fn handle_output(
                     truncated[i] = true;
                 }
                 let message = stream_id(ByteBuf::from(&buf[..len]));
-                connection.lock().unwrap().output_message(message)?;
+                output.lock().unwrap().output(message)?;
             }
         }
 
@@ -442,13 +677,17 @@ fn handle_output(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_child(
     request: &Request,
     language: &Language,
+    version: &Version,
+    limits: &Limits,
     stdout_w: i32,
     stderr_w: i32,
-    outside_uid: Uid,
-    outside_gid: Gid,
+    seccomp_w: i32,
+    ids_r: i32,
+    egress_w: Option<i32>,
 ) -> () {
     // to have reliable error reporting, the state of stdout and stderr must be managed carefully:
 
@@ -472,7 +711,7 @@ fn run_child(
 
     // TODO: simplify this, because load_env and setup_child only ever return InternalError
 
-    let env = match load_env(&language) {
+    let mut env = match load_env(version) {
         Ok(r) => r,
         Err(e) => {
             if let Error::InternalError(e) = e {
@@ -481,8 +720,16 @@ fn run_child(
             return;
         }
     };
+    if request.deterministic {
+        override_env(&mut env, DETERMINISTIC_ENV);
+    }
+    if egress_w.is_some() {
+        override_env(&mut env, egress::PROXY_ENV);
+    }
 
-    if let Err(e) = setup_child(&request, &language, outside_uid, outside_gid) {
+    if let Err(e) = setup_child(
+        request, language, version, limits, seccomp_w, ids_r, egress_w,
+    ) {
         if let Error::InternalError(e) = e {
             log_error!("{e}");
         }
@@ -510,59 +757,178 @@ fn run_child(
     eprintln!("ATO internal error: error running execve: {e}")
 }
 
-fn load_env(language: &Language) -> Result<Vec<CString>, Error> {
-    const ENV_BASE_PATH: &str = "/usr/local/lib/ATO/env/";
-    let path = String::from(ENV_BASE_PATH) + &language.image.replace("/", "+").replace(":", "+");
-    check!(std::fs::read(path), "error reading image env file: {}")
-        .split_inclusive(|b| *b == 0) // split after null bytes, and include them in the results
-        .map(|s| CString::from_vec_with_nul(s.to_vec()))
-        .collect::<Result<Vec<_>, _>>() // collects errors too
-        .map_err(|e| Error::InternalError(format!("error building env string: {e}")))
+fn load_env(version: &Version) -> Result<Vec<CString>, Error> {
+    check!(
+        std::fs::read(version.env_file()),
+        "error reading image env file: {}"
+    )
+    .split_inclusive(|b| *b == 0) // split after null bytes, and include them in the results
+    .map(|s| CString::from_vec_with_nul(s.to_vec()))
+    .collect::<Result<Vec<_>, _>>() // collects errors too
+    .map_err(|e| Error::InternalError(format!("error building env string: {e}")))
 }
 
 fn setup_child(
     request: &Request,
     language: &Language,
-    outside_uid: Uid,
-    outside_gid: Gid,
+    version: &Version,
+    limits: &Limits,
+    seccomp_w: i32,
+    ids_r: i32,
+    egress_w: Option<i32>,
 ) -> Result<(), Error> {
     const SIGKILL: i32 = 9;
     // set up to die if our parent dies
+    trace!("setting parent death signal");
     check!(
         prctl::set_pdeathsig(Some(SIGKILL)),
         "error setting parent death signal: {}"
     );
 
-    set_ids(outside_uid, outside_gid)?;
+    trace!("waiting for ID mappings");
+    set_ids(ids_r)?;
+    if request.deterministic {
+        trace!("entering time namespace");
+        enter_time_namespace()?;
+    }
+    trace!("setting up network");
     setup_network()?;
-    setup_filesystem(&request, &language)?;
-    drop_caps()?;
-    set_resource_limits()?;
+    if let Some(egress_w) = egress_w {
+        trace!("listening for egress proxy connections");
+        egress::listen(egress_w)?;
+    }
+    setup_filesystem(request, language, version, limits)?;
+    trace!("dropping capabilities");
+    drop_caps(unprivileged_user(request, language))?;
+    trace!("restricting filesystem access");
+    restrict_filesystem()?;
+    trace!("setting resource limits");
+    set_resource_limits(limits)?;
+    trace!("installing seccomp filter");
+    seccomp::install(language, seccomp_w)?;
+    trace!("running /ATO/runner");
     Ok(())
 }
 
-fn set_ids(outside_uid: Uid, outside_gid: Gid) -> Result<(), Error> {
-    // we're currently nobody (65534) inside the container which means we can't do anything.
-    // so we declare ourselves root inside the container, but this requires a mapping for who we become from the perspective of processes outside the container
+/// environment variables for deterministic mode, which replace any the image sets
+const DETERMINISTIC_ENV: &[(&str, &str)] = &[
+    ("ATO_DETERMINISTIC", "1"),
+    ("ATO_SEED", "0"),
+    // the wall clock can't be changed, but this is used instead by programs which support it
+    // (2000-01-01T00:00:00Z)
+    ("SOURCE_DATE_EPOCH", "946684800"),
+    ("TZ", "UTC"),
+    // some interpreters randomise the order of hash tables unless told otherwise
+    ("PYTHONHASHSEED", "0"),
+    ("PERL_HASH_SEED", "0"),
+    ("PERL_PERTURB_KEYS", "0"),
+];
+
+/// set environment variables, replacing any existing ones with the same names
+fn override_env(env: &mut Vec<CString>, vars: &[(&str, &str)]) {
+    env.retain(|var| {
+        !vars
+            .iter()
+            .any(|(name, _)| var.to_bytes().starts_with(format!("{name}=").as_bytes()))
+    });
+    env.extend(vars.iter().map(|(name, value)| {
+        CString::new(format!("{name}={value}")).expect("environment variable contains null byte")
+    }));
+}
+
+/// Move into a new time namespace in which the monotonic and boot-time clocks start from zero, so
+/// that programs can't tell how long the host has been up.
+///
+/// This can't be done by passing CLONE_NEWTIME to clone3, because the clocks' offsets can only be
+/// set before any process is in the namespace. Instead, the namespace is created for our children,
+/// and then we join it ourselves.
+fn enter_time_namespace() -> Result<(), Error> {
+    check!(
+        // this is safe because it doesn't involve any memory
+        Errno::result(unsafe { libc::unshare(libc::CLONE_NEWTIME) }),
+        "error creating time namespace: {}"
+    );
+    let monotonic = check!(
+        clock_gettime(ClockId::CLOCK_MONOTONIC),
+        "error getting monotonic time: {}"
+    );
+    let boottime = check!(
+        clock_gettime(ClockId::CLOCK_BOOTTIME),
+        "error getting boot time: {}"
+    );
+    check!(
+        std::fs::write(
+            "/proc/self/timens_offsets",
+            format!(
+                "monotonic {} 0\nboottime {} 0\n",
+                -monotonic.tv_sec(),
+                -boottime.tv_sec()
+            )
+        ),
+        "error writing time namespace offsets: {}"
+    );
+    let namespace = check!(
+        File::open("/proc/self/ns/time_for_children"),
+        "error opening time namespace: {}"
+    );
+    check!(
+        // this is safe because it doesn't involve any memory
+        Errno::result(unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWTIME) }),
+        "error entering time namespace: {}"
+    );
+    Ok(())
+}
 
-    const ROOT_U: Uid = Uid::from_raw(0);
-    const ROOT_G: Gid = Gid::from_raw(0);
+const ROOT_U: Uid = Uid::from_raw(0);
+const ROOT_G: Gid = Gid::from_raw(0);
+/// the unprivileged user inside the container, which is `nobody` in almost every image
+const UNPRIVILEGED_U: Uid = Uid::from_raw(65534);
+const UNPRIVILEGED_G: Gid = Gid::from_raw(65534);
+
+/// whether the runner can switch to the unprivileged user
+fn unprivileged_user(request: &Request, language: &Language) -> bool {
+    request
+        .unprivileged_user
+        .unwrap_or(language.unprivileged_user)
+}
 
-    let uid_map = format!("{ROOT_U} {outside_uid} 1\n");
+/// Map root inside the container (and the unprivileged user, if requested) to the sandbox's users
+/// and groups outside it. This is done by the zygote rather than the child, because mapping to any
+/// ID other than the zygote's own requires CAP_SETUID and CAP_SETGID outside the container.
+fn map_ids(pid: i32, ids: &Ids, unprivileged: bool) -> Result<(), Error> {
+    let mut uid_map = format!("{ROOT_U} {} 1\n", ids.uid);
+    let mut gid_map = format!("{ROOT_G} {} 1\n", ids.gid);
+    if let (true, Some((uid, gid))) = (unprivileged, ids.unprivileged) {
+        uid_map += &format!("{UNPRIVILEGED_U} {uid} 1\n");
+        gid_map += &format!("{UNPRIVILEGED_G} {gid} 1\n");
+    }
     check!(
-        std::fs::write("/proc/self/uid_map", uid_map),
+        std::fs::write(format!("/proc/{pid}/uid_map"), uid_map),
         "error writing uid_map: {}"
     );
-    // we need to set this in order to be able to use gid_map
+    // we need to set this in order to be able to use gid_map without CAP_SETGID
     check!(
-        std::fs::write("/proc/self/setgroups", "deny"),
+        std::fs::write(format!("/proc/{pid}/setgroups"), "deny"),
         "error denying setgroups: {}"
     );
-    let gid_map = format!("{ROOT_G} {outside_gid} 1\n");
     check!(
-        std::fs::write("/proc/self/gid_map", gid_map),
+        std::fs::write(format!("/proc/{pid}/gid_map"), gid_map),
         "error writing gid_map: {}"
     );
+    Ok(())
+}
+
+fn set_ids(ids_r: i32) -> Result<(), Error> {
+    // we're currently nobody (65534) inside the container which means we can't do anything.
+    // so we declare ourselves root inside the container, once the zygote has mapped root to who we
+    // become from the perspective of processes outside the container (see map_ids)
+    let mut buf = [0];
+    if check!(read(ids_r, &mut buf), "error waiting for ID mappings: {}") == 0 {
+        return Err(Error::InternalError(
+            "error waiting for ID mappings: zygote failed to write them".into(),
+        ));
+    }
+    check!(close(ids_r), "error closing ID mapping pipe read end: {}");
 
     check!(setresuid(ROOT_U, ROOT_U, ROOT_U), "error setting UIDs: {}");
     check!(setresgid(ROOT_G, ROOT_G, ROOT_G), "error setting GIDs: {}");
@@ -571,41 +937,39 @@ fn set_ids(outside_uid: Uid, outside_gid: Gid) -> Result<(), Error> {
 
 macro_rules! mount_ {
     ($src:expr, $dest:expr, $type:expr, $($flag:ident)|*, $options:expr) => {
+        trace!("mounting {}", $dest);
         check!(mount::<str, str, str, str>($src, $dest, $type, MsFlags::empty() $(| MsFlags::$flag)*, $options), "error mounting {}: {}", $dest)
     }
 }
 macro_rules! mount {
     ($dest:literal, $type:literal, $($flag:ident)|*) =>
         { mount_!(Some($type), $dest, Some($type), $($flag)|*, None) };
-    ($dest:expr, $type:literal, $($flag:ident)|*, $options:literal) =>
+    ($dest:expr, $type:literal, $($flag:ident)|*, $options:expr) =>
         { mount_!(Some($type), $dest, Some($type), $($flag)|*, Some($options)) };
     ($src:expr, $dest:expr, $type:expr, $($flag:ident)|*) =>
         { mount_!(Some($src), $dest, Some($type), $($flag)|*, None) };
     ($src:expr, $dest:expr, , $($flag:ident)|*) =>
         { mount_!(Some($src), $dest, None, $($flag)|*, None) };
-    ($src:expr, $dest:expr, $type:expr, $($flag:ident)|*, $options:literal) =>
+    ($src:expr, $dest:expr, $type:expr, $($flag:ident)|*, $options:expr) =>
         { mount_!(Some($src), $dest, Some($type), $($flag)|*, Some($options)) };
 }
 
-fn get_rootfs(language: &Language) -> String {
-    const IMAGE_BASE_PATH: &str = "/usr/local/lib/ATO/rootfs/";
-    String::from(IMAGE_BASE_PATH) + &language.image.replace("/", "+").replace(":", "+")
-}
-
-fn get_default_runner(language_id: &String) -> String {
-    const LANGUAGE_BASE_PATH: &str = "/usr/local/share/ATO/runners/";
-    String::from(LANGUAGE_BASE_PATH) + language_id
-}
-
-fn setup_filesystem(request: &Request, language: &Language) -> Result<(), Error> {
+fn setup_filesystem(
+    request: &Request,
+    language: &Language,
+    version: &Version,
+    limits: &Limits,
+) -> Result<(), Error> {
     // find out where the languages' image is stored
-    let rootfs = get_rootfs(&language);
+    let rootfs = version.rootfs();
+    trace!("setting up filesystem from {rootfs}");
 
     // set the propogation type of all mounts to private - this is because:
     // 1. when we mount /run/ATO, and bind-mount other stuff,
     //    we don't want those to propogate to the parent namespace
     // 2. we don't want any potential mounts in the parent namespace to appear here and mess things up either
     // 3. pivot_root below requires . and its parent to be mounted private anyway
+    trace!("making all mounts private");
     check!(
         mount::<str, str, str, str>(None, "/", None, MsFlags::MS_PRIVATE | MsFlags::MS_REC, None,),
         "error setting / to MS_PRIVATE: {}"
@@ -613,8 +977,14 @@ fn setup_filesystem(request: &Request, language: &Language) -> Result<(), Error>
 
     // mount a tmpfs to contain the data written to the container's root filesystem
     // (which will be discarded when the container exits)
-    mount!("/run/ATO", "tmpfs", MS_NOSUID, "mode=755,size=655350k");
+    mount!(
+        "/run/ATO",
+        "tmpfs",
+        MS_NOSUID,
+        &tmpfs_options("755", limits)
+    );
     // overlayfs requires separate "upper" and "work" directories, so create those
+    trace!("creating overlayfs directories");
     check!(
         mkdir("/run/ATO/upper", Mode::S_IRWXU),
         "error creating overlayfs upper directory: {}"
@@ -631,6 +1001,7 @@ fn setup_filesystem(request: &Request, language: &Language) -> Result<(), Error>
 
     // mount writeable upper layer on top of rootfs using overlayfs
     // also, the kernel now considers it a mount point, which is required for pivot_root to work
+    trace!("mounting new rootfs");
     check!(
         mount::<str, str, str, str>(
             None,
@@ -650,14 +1021,18 @@ fn setup_filesystem(request: &Request, language: &Language) -> Result<(), Error>
     );
     // now . points to the new rootfs
 
-    setup_special_files(&request.language)?;
+    setup_special_files(version, limits)?;
 
     // swap (or "pivot") the meanings of / and .
     // so now, / points to the new container rootfs, and . points to the old system root
     // (note that this means . is not actually anywhere in the directory tree!)
+    trace!("pivoting root");
     check!(pivot_root(".", "."), "error pivoting root: {}");
 
-    setup_request_files(&request)?;
+    trace!("writing request files");
+    setup_request_files(request, language)?;
+    trace!("writing network files");
+    setup_network_files()?;
 
     // cwd after pivot_root is not well-defined, so we have to go somewhere
     // since we need to go to /ATO at some point anyway, let's got there
@@ -665,18 +1040,24 @@ fn setup_filesystem(request: &Request, language: &Language) -> Result<(), Error>
     Ok(())
 }
 
-fn setup_special_files(language_id: &String) -> Result<(), Error> {
+/// The mount options for a writable tmpfs with the given mode.
+fn tmpfs_options(mode: &str, limits: &Limits) -> String {
+    format!("mode={mode},size={}k", limits.tmpfs / KiB)
+}
+
+fn setup_special_files(version: &Version, limits: &Limits) -> Result<(), Error> {
+    trace!("setting up special files");
     mount!(
         "./tmp",
         "tmpfs",
         MS_NOSUID | MS_NODEV,
-        "mode=1755,size=655350k"
+        &tmpfs_options("1777", limits)
     );
     mount!(
         "./ATO",
         "tmpfs",
         MS_NOSUID | MS_NODEV,
-        "mode=755,size=655350k"
+        &tmpfs_options("755", limits)
     );
     check!(
         mkdir(
@@ -690,7 +1071,7 @@ fn setup_special_files(language_id: &String) -> Result<(), Error> {
         "./dev",
         "tmpfs",
         MS_NOSUID | MS_STRICTATIME,
-        "mode=755,size=655350k"
+        &tmpfs_options("755", limits)
     );
     check!(
         mkdir("./dev/pts", Mode::empty()),
@@ -711,19 +1092,25 @@ fn setup_special_files(language_id: &String) -> Result<(), Error> {
         "./dev/shm",
         "tmpfs",
         MS_NOSUID | MS_NODEV | MS_NOEXEC,
-        "mode=1777,size=655350k"
+        &tmpfs_options("1777", limits)
     );
     check!(
         mkdir("./dev/mqueue", Mode::empty()),
         "error creating mount point for /dev/mqueue: {}"
     );
     mount!("./dev/mqueue", "mqueue", MS_NOSUID | MS_NODEV | MS_NOEXEC);
-    mount!("./sys", "sysfs", MS_NOSUID | MS_NODEV | MS_NOEXEC);
+    mount!(
+        "./sys",
+        "sysfs",
+        MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC
+    );
     mount!(
         "./sys/fs/cgroup",
         "cgroup2",
-        MS_NOSUID | MS_NODEV | MS_NOEXEC | MS_RELATIME
+        MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC | MS_RELATIME
     );
+    trace!("masking paths");
+    mask_paths()?;
 
     // create all the special device files expected on a "real" Linux system
 
@@ -738,6 +1125,7 @@ fn setup_special_files(language_id: &String) -> Result<(), Error> {
         mount!(&src, &dest, , MS_NOSUID | MS_NOEXEC | MS_BIND);
     }
 
+    trace!("creating symlinks in /dev");
     check!(
         symlinkat("/proc/self/fd", None, "dev/fd"),
         "error creating /dev/fd: {}"
@@ -754,10 +1142,6 @@ fn setup_special_files(language_id: &String) -> Result<(), Error> {
         symlinkat("/proc/self/fd/2", None, "dev/stderr"),
         "error creating /dev/stderr: {}"
     );
-    check!(
-        symlinkat("/proc/kcore", None, "dev/core"),
-        "error creating /dev/core: {}"
-    );
     check!(
         symlinkat("pts/ptmx", None, "dev/ptmx"),
         "error creating /dev/ptmx: {}"
@@ -766,7 +1150,8 @@ fn setup_special_files(language_id: &String) -> Result<(), Error> {
     for (src, dest) in [
         ("/usr/local/lib/ATO/bash", "./ATO/bash"),
         ("/usr/local/lib/ATO/yargs", "./ATO/yargs"),
-        (&get_default_runner(&language_id), "./ATO/default_runner"),
+        ("/usr/local/lib/ATO/unprivileged", "./ATO/unprivileged"),
+        (&version.runner_path(), "./ATO/default_runner"),
     ] {
         drop(check!(
             File::create(&dest),
@@ -779,7 +1164,86 @@ fn setup_special_files(language_id: &String) -> Result<(), Error> {
     Ok(())
 }
 
-fn setup_request_files(request: &Request) -> Result<(), Error> {
+/// Paths which would let programs find out about the host, or poke at kernel interfaces. These are
+/// the same as the defaults for OCI runtimes, such as Docker's.
+const MASKED_PATHS: &[&str] = &[
+    "proc/acpi",
+    "proc/asound",
+    "proc/kcore",
+    "proc/keys",
+    "proc/latency_stats",
+    "proc/sched_debug",
+    "proc/scsi",
+    "proc/sysrq-trigger",
+    "proc/timer_list",
+    "proc/timer_stats",
+    "sys/devices/virtual/powercap",
+    "sys/firmware",
+];
+/// Paths in /proc which are made read-only (/sys is mounted read-only as a whole)
+const READ_ONLY_PATHS: &[&str] = &["proc/bus", "proc/fs", "proc/irq", "proc/sys"];
+
+/// Hide the paths in MASKED_PATHS by mounting something empty over them, and make the paths in
+/// READ_ONLY_PATHS read-only. Paths which don't exist on this kernel are skipped.
+fn mask_paths() -> Result<(), Error> {
+    for path in MASKED_PATHS {
+        let path = format!("./{path}");
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::InternalError(format!("error checking {path}: {e}"))),
+        };
+        if metadata.is_dir() {
+            mount!(
+                "tmpfs",
+                &path,
+                "tmpfs",
+                MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC,
+                "mode=555"
+            );
+        } else {
+            mount!("/dev/null", &path, , MS_BIND);
+            remount_read_only(&path)?;
+        }
+    }
+    for path in READ_ONLY_PATHS {
+        let path = format!("./{path}");
+        if !check!(std::fs::exists(&path), "error checking {}: {}", path) {
+            continue;
+        }
+        mount!(&path, &path, , MS_BIND | MS_REC);
+        remount_read_only(&path)?;
+    }
+    Ok(())
+}
+
+/// Make a bind mount read-only. MS_RDONLY is ignored when a bind mount is first created, so this has
+/// to be done separately, and the mount's other flags have to be kept, since they may be locked (see
+/// mount_namespaces(7)).
+fn remount_read_only(path: &str) -> Result<(), Error> {
+    let flags = check!(statvfs(path), "error checking mount flags of {}: {}", path).flags();
+    let mut new_flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (flag, new_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if flags.contains(flag) {
+            new_flags |= new_flag;
+        }
+    }
+    check!(
+        mount::<str, str, str, str>(None, path, None, new_flags, None),
+        "error making {} read-only: {}",
+        path
+    );
+    Ok(())
+}
+
+fn setup_request_files(request: &Request, language: &Language) -> Result<(), Error> {
     check!(
         std::fs::write("/ATO/code", &request.code),
         "error writing /ATO/code: {}"
@@ -809,8 +1273,17 @@ fn setup_request_files(request: &Request) -> Result<(), Error> {
         std::fs::write("/ATO/arguments", join_args(&request.arguments)),
         "error writing /ATO/arguments: {}"
     );
+    let options = if request.options.is_empty() {
+        let options = &language.default_options;
+        options
+            .iter()
+            .map(|arg| ByteBuf::from(arg.as_bytes()))
+            .collect()
+    } else {
+        request.options.clone()
+    };
     check!(
-        std::fs::write("/ATO/options", join_args(&request.options)),
+        std::fs::write("/ATO/options", join_args(&options)),
         "error writing /ATO/options: {}"
     );
     Ok(())
@@ -825,16 +1298,32 @@ fn join_args(args: &Vec<ByteBuf>) -> ByteBuf {
     b
 }
 
-fn drop_caps() -> Result<(), Error> {
+fn drop_caps(unprivileged_user: bool) -> Result<(), Error> {
     // drop as many privileges as possible
     // for more info on caps, see man capabilities(7)
 
+    // the runner needs these to switch to the unprivileged user. They don't matter outside the
+    // container, where they only cover the two IDs which are mapped into it
+    let keep: caps::CapSet = if unprivileged_user {
+        [caps::Cap::SETUID, caps::Cap::SETGID].into_iter().collect()
+    } else {
+        caps::CapSet::empty()
+    };
+
     // important: make sure this code is written to drop all capabilities, even those we may not
     // know about at compile time because of a kernel version mismatch.
     // https://docs.rs/capctl/latest/capctl/#handling-of-newly-added-capabilities
 
+    for cap in caps::Cap::probe_supported().iter() {
+        if !keep.has(cap) {
+            check!(
+                caps::bounding::drop(cap),
+                "error dropping bounding capabilities: {}"
+            );
+        }
+    }
     check!(
-        caps::bounding::clear(),
+        caps::bounding::clear_unknown(),
         "error dropping bounding capabilities: {}"
     );
     check!(
@@ -843,7 +1332,12 @@ fn drop_caps() -> Result<(), Error> {
     );
     // CAP_SETPCAP is required to drop bounding caps, so we have to drop it last
     check!(
-        caps::CapState::empty().set_current(),
+        caps::CapState {
+            effective: keep,
+            permitted: keep,
+            inheritable: caps::CapSet::empty(),
+        }
+        .set_current(),
         "error dropping capabilities: {}"
     );
     // ensure we can't get new privileges from files with set[ug]id or capability xattrs
@@ -854,7 +1348,43 @@ fn drop_caps() -> Result<(), Error> {
     Ok(())
 }
 
-fn set_resource_limits() -> Result<(), Error> {
+/// Directories which programs are allowed to write to
+const WRITABLE_PATHS: &[&str] = &["/ATO", "/tmp", "/dev/shm"];
+
+/// Use Landlock to stop programs from writing anywhere except WRITABLE_PATHS. This is a second
+/// barrier in case a mistake in the mounts ever exposes part of the host's file system. Landlock is
+/// skipped on kernels which don't support it.
+fn restrict_filesystem() -> Result<(), Error> {
+    let abi = ABI::V5;
+    let ruleset = check!(
+        Ruleset::default().handle_access(AccessFs::from_all(abi)),
+        "error creating landlock ruleset: {}"
+    );
+    let ruleset = check!(ruleset.create(), "error creating landlock ruleset: {}");
+    let ruleset = check!(
+        ruleset.add_rules(path_beneath_rules(["/"], AccessFs::from_read(abi))),
+        "error adding landlock rule: {}"
+    );
+    let ruleset = check!(
+        ruleset.add_rules(path_beneath_rules(WRITABLE_PATHS, AccessFs::from_all(abi))),
+        "error adding landlock rule: {}"
+    );
+    // the device files in /dev (like /dev/null) must still be writable, but nothing can be created
+    let ruleset = check!(
+        ruleset.add_rules(path_beneath_rules(
+            ["/dev"],
+            AccessFs::WriteFile | AccessFs::Truncate | AccessFs::IoctlDev
+        )),
+        "error adding landlock rule: {}"
+    );
+    check!(
+        ruleset.restrict_self(),
+        "error applying landlock ruleset: {}"
+    );
+    Ok(())
+}
+
+fn set_resource_limits(limits: &Limits) -> Result<(), Error> {
     // resource limits work as follows: each one has a "soft" and "hard" limit.
     // for most limits, the process will get an error if it goes beyond the soft limit;
     // the soft limit can be raised by the process but never to higher than the hard limit.
@@ -864,18 +1394,24 @@ fn set_resource_limits() -> Result<(), Error> {
     // if the process reaches the soft limit it will get a SIGXCPU warning signal
     // if it reaches the hard limit it will be forcibly killed
     // TODO: does setting a CPU-time rlimit even work?
+    let cpu_limit = limits.timeout as u64;
     check!(
-        setrlimit(Resource::RLIMIT_CPU, 60, 61),
+        setrlimit(Resource::RLIMIT_CPU, cpu_limit, cpu_limit + 1),
         "error setting CPU resource limit: {}"
     );
     // number of processes/threads, to prevent exhaustion of kernel resources
     check!(
-        setrlimit(Resource::RLIMIT_NPROC, 100, 100),
+        setrlimit(Resource::RLIMIT_NPROC, limits.pids, limits.pids),
         "error setting NPROC resource limit: {}"
     );
     // written file size, to prevent memory exhaustion by filling up a tmpfs
+    // (the soft limit is 15/16 of the hard limit, so the program gets a SIGXFSZ warning first)
     check!(
-        setrlimit(Resource::RLIMIT_FSIZE, 120 * MiB, 128 * MiB),
+        setrlimit(
+            Resource::RLIMIT_FSIZE,
+            limits.file_size / 16 * 15,
+            limits.file_size
+        ),
         "error setting FSIZE resource limit: {}"
     );
 
@@ -0,0 +1,403 @@
+//! A seccomp filter which stops sandboxed programs from making system calls that they rarely need,
+//! but which are often involved in container escapes.
+//!
+//! Denied calls fail with EPERM, as in Docker's default profile, rather than killing the program,
+//! because some language runtimes try them and fall back to something else. So that the client can
+//! be told which calls were denied, the filter hands them to the zygote as user notifications, and
+//! the zygote records them and answers with the error.
+
+use crate::{Error, check, languages::Language};
+use nix::{
+    errno::Errno,
+    libc,
+    poll::PollFlags,
+    sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg},
+    unistd::close,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+/// system calls which are denied unless a language allows them
+const DEFAULT_DENIED: &[&str] = &[
+    "add_key",
+    "bpf",
+    "io_uring_enter",
+    "io_uring_register",
+    "io_uring_setup",
+    "keyctl",
+    "open_by_handle_at",
+    "perf_event_open",
+    "request_key",
+    "userfaultfd",
+];
+
+/// the system calls which can be named in languages.json
+const SYSCALLS: &[(&str, libc::c_long)] = &[
+    ("acct", libc::SYS_acct),
+    ("add_key", libc::SYS_add_key),
+    ("bpf", libc::SYS_bpf),
+    ("chroot", libc::SYS_chroot),
+    ("delete_module", libc::SYS_delete_module),
+    ("fanotify_init", libc::SYS_fanotify_init),
+    ("finit_module", libc::SYS_finit_module),
+    ("init_module", libc::SYS_init_module),
+    ("io_uring_enter", libc::SYS_io_uring_enter),
+    ("io_uring_register", libc::SYS_io_uring_register),
+    ("io_uring_setup", libc::SYS_io_uring_setup),
+    ("kexec_file_load", libc::SYS_kexec_file_load),
+    ("kexec_load", libc::SYS_kexec_load),
+    ("keyctl", libc::SYS_keyctl),
+    ("mount", libc::SYS_mount),
+    ("name_to_handle_at", libc::SYS_name_to_handle_at),
+    ("open_by_handle_at", libc::SYS_open_by_handle_at),
+    ("perf_event_open", libc::SYS_perf_event_open),
+    ("personality", libc::SYS_personality),
+    ("pivot_root", libc::SYS_pivot_root),
+    ("process_vm_readv", libc::SYS_process_vm_readv),
+    ("process_vm_writev", libc::SYS_process_vm_writev),
+    ("ptrace", libc::SYS_ptrace),
+    ("quotactl", libc::SYS_quotactl),
+    ("reboot", libc::SYS_reboot),
+    ("request_key", libc::SYS_request_key),
+    ("setns", libc::SYS_setns),
+    ("swapoff", libc::SYS_swapoff),
+    ("swapon", libc::SYS_swapon),
+    ("syslog", libc::SYS_syslog),
+    ("umount2", libc::SYS_umount2),
+    ("unshare", libc::SYS_unshare),
+    ("userfaultfd", libc::SYS_userfaultfd),
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e; // AUDIT_ARCH_X86_64
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7; // AUDIT_ARCH_AARCH64
+
+/// set in the numbers of x32 system calls, which are all denied so they can't be used to get around
+/// the filter
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// these aren't in the libc crate yet
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+nix::ioctl_readwrite!(notif_recv, b'!', 0, libc::seccomp_notif);
+nix::ioctl_readwrite!(notif_send, b'!', 1, libc::seccomp_notif_resp);
+
+fn number(name: &str) -> Option<libc::c_long> {
+    SYSCALLS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, number)| number)
+}
+
+fn name(number: libc::c_long) -> String {
+    SYSCALLS
+        .iter()
+        .find(|(_, n)| *n == number)
+        .map_or_else(|| format!("syscall {number}"), |(name, _)| name.to_string())
+}
+
+/// Make sure every system call named in languages.json is one we know about.
+pub fn check_languages(languages: &HashMap<String, Language>) -> Result<(), String> {
+    for (id, language) in languages {
+        for syscall in language
+            .deny_syscalls
+            .iter()
+            .chain(&language.allow_syscalls)
+        {
+            if number(syscall).is_none() {
+                return Err(format!("unknown system call for {id}: {syscall}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn denied(language: &Language) -> BTreeSet<libc::c_long> {
+    DEFAULT_DENIED
+        .iter()
+        .copied()
+        .filter(|syscall| !language.allow_syscalls.iter().any(|s| s == syscall))
+        .chain(language.deny_syscalls.iter().map(String::as_str))
+        .filter_map(number)
+        .collect()
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn filter(denied: &BTreeSet<libc::c_long>) -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let arch = std::mem::offset_of!(libc::seccomp_data, arch) as u32;
+    let nr = std::mem::offset_of!(libc::seccomp_data, nr) as u32;
+    let mut filter = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, arch),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD | BPF_W | BPF_ABS, nr),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(
+            BPF_RET | BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+        ),
+    ]);
+    for (i, &number) in denied.iter().enumerate() {
+        // skip the rest of the comparisons and the ALLOW, to get to the notification
+        let skip = (denied.len() - i) as u8;
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, number as u32, skip, 0));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_USER_NOTIF));
+    filter
+}
+
+/// Install the filter in the sandbox's main process, and send its listener to the zygote through
+/// the socket. Must be called after dropping capabilities, and right before execve.
+pub fn install(language: &Language, socket: RawFd) -> Result<(), Error> {
+    let mut filter = filter(&denied(language));
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // this is safe because the pointer is to a valid local
+    let listener = check!(
+        Errno::result(unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_NEW_LISTENER,
+                &program,
+            )
+        }),
+        "error installing seccomp filter: {}"
+    ) as RawFd;
+    check!(
+        sendmsg::<()>(
+            socket,
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(&[listener])],
+            MsgFlags::empty(),
+            None
+        ),
+        "error sending seccomp listener: {}"
+    );
+    // the socket is closed along with everything else before execve
+    check!(close(listener), "error closing seccomp listener: {}");
+    Ok(())
+}
+
+/// The zygote's side of a sandbox's seccomp filter.
+pub struct Monitor {
+    // receives the filter's listener from the sandbox, once it has been installed
+    socket: Option<OwnedFd>,
+    listener: Option<OwnedFd>,
+    /// the system calls which have been denied
+    pub blocked: BTreeSet<String>,
+}
+
+impl Monitor {
+    pub fn new(socket: OwnedFd) -> Self {
+        Self {
+            socket: Some(socket),
+            listener: None,
+            blocked: BTreeSet::new(),
+        }
+    }
+
+    /// the file descriptor to wait on, or None once the sandbox can't make any more system calls
+    pub fn fd(&self) -> Option<RawFd> {
+        self.listener
+            .as_ref()
+            .or(self.socket.as_ref())
+            .map(AsRawFd::as_raw_fd)
+    }
+
+    /// Respond to events on fd().
+    pub fn handle(&mut self, events: PollFlags) {
+        if let Some(listener) = &self.listener {
+            if events.contains(PollFlags::POLLIN) {
+                self.answer(listener.as_raw_fd());
+            } else {
+                // every process using the filter has exited
+                self.listener = None;
+            }
+        } else if let Some(socket) = self.socket.take() {
+            // if the sandbox failed before installing the filter, there's nothing more to do
+            self.listener = receive_listener(socket.as_raw_fd());
+        }
+    }
+
+    fn answer(&mut self, listener: RawFd) {
+        // this is safe because an all-zero seccomp_notif is valid (and in fact required)
+        let mut notification: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+        // this is safe because the pointer is to a valid local
+        if unsafe { notif_recv(listener, &mut notification) }.is_err() {
+            // the process making the call has already been killed
+            return;
+        }
+        self.blocked
+            .insert(name(notification.data.nr as libc::c_long));
+        let mut response = libc::seccomp_notif_resp {
+            id: notification.id,
+            val: 0,
+            error: -libc::EPERM,
+            flags: 0,
+        };
+        // this is safe because the pointer is to a valid local. If it fails, the process has been
+        // killed, so it doesn't matter
+        let _ = unsafe { notif_send(listener, &mut response) };
+    }
+}
+
+fn receive_listener(socket: RawFd) -> Option<OwnedFd> {
+    let mut buf = [0];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_space = nix::cmsg_space!(RawFd);
+    let message = recvmsg::<()>(
+        socket,
+        &mut iov,
+        Some(&mut cmsg_space),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .ok()?;
+    message.cmsgs().find_map(|cmsg| match cmsg {
+        // this is safe because the fd was just received, so nothing else owns it
+        ControlMessageOwned::ScmRights(fds) => {
+            fds.first().map(|&fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(deny: &[&str], allow: &[&str]) -> Language {
+        serde_json::from_value(serde_json::json!({
+            "image": "test",
+            "deny_syscalls": deny,
+            "allow_syscalls": allow,
+        }))
+        .unwrap()
+    }
+
+    /// run the filter like the kernel would, for the few instructions it uses
+    fn run(filter: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = filter[pc];
+            pc += 1;
+            match instruction.code as u32 {
+                code if code == BPF_LD | BPF_W | BPF_ABS => {
+                    accumulator = if instruction.k == 0 { nr } else { arch };
+                }
+                code if code == BPF_JMP | BPF_JEQ | BPF_K || code == BPF_JMP | BPF_JGE | BPF_K => {
+                    let taken = if code & 0xf0 == BPF_JEQ {
+                        accumulator == instruction.k
+                    } else {
+                        accumulator >= instruction.k
+                    };
+                    pc += if taken {
+                        instruction.jt
+                    } else {
+                        instruction.jf
+                    } as usize;
+                }
+                code if code == BPF_RET | BPF_K => return instruction.k,
+                code => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    fn denied_names(deny: &[&str], allow: &[&str]) -> BTreeSet<String> {
+        denied(&language(deny, allow))
+            .into_iter()
+            .map(name)
+            .collect()
+    }
+
+    #[test]
+    fn allow_and_deny_merged_with_defaults() {
+        let defaults = DEFAULT_DENIED.iter().map(|s| s.to_string()).collect();
+        assert_eq!(denied_names(&[], &[]), defaults);
+        let denied = denied_names(&["ptrace", "bpf"], &["bpf", "keyctl", "mount"]);
+        assert!(denied.contains("ptrace"));
+        // denying wins over allowing
+        assert!(denied.contains("bpf"));
+        assert!(!denied.contains("keyctl"));
+        // allowing something which isn't denied anyway does nothing
+        assert!(!denied.contains("mount"));
+        assert!(denied.contains("userfaultfd"));
+    }
+
+    #[test]
+    fn unknown_syscalls_rejected() {
+        let mut languages = HashMap::new();
+        languages.insert("ok".to_string(), language(&["ptrace"], &["bpf"]));
+        assert_eq!(check_languages(&languages), Ok(()));
+        languages.insert("denies".to_string(), language(&["fork"], &[]));
+        assert_eq!(
+            check_languages(&languages),
+            Err("unknown system call for denies: fork".to_string())
+        );
+        languages.remove("denies");
+        languages.insert("allows".to_string(), language(&[], &["not_a_syscall"]));
+        assert_eq!(
+            check_languages(&languages),
+            Err("unknown system call for allows: not_a_syscall".to_string())
+        );
+    }
+
+    #[test]
+    fn filter_results() {
+        let filter = filter(&denied(&language(&["ptrace"], &[])));
+        let allow = libc::SECCOMP_RET_ALLOW;
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_read as u32), allow);
+        assert_eq!(
+            run(&filter, AUDIT_ARCH, libc::SYS_ptrace as u32),
+            SECCOMP_RET_USER_NOTIF
+        );
+        assert_eq!(
+            run(&filter, AUDIT_ARCH, libc::SYS_bpf as u32),
+            SECCOMP_RET_USER_NOTIF
+        );
+        // other architectures' numbers mean different calls
+        assert_eq!(
+            run(&filter, 0x4000_0003, libc::SYS_read as u32),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x32_syscalls_rejected() {
+        let filter = filter(&denied(&language(&[], &[])));
+        let enosys = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+        for number in [libc::SYS_read, libc::SYS_bpf, libc::SYS_keyctl] {
+            let x32 = number as u32 | X32_SYSCALL_BIT;
+            assert_eq!(run(&filter, AUDIT_ARCH, x32), enosys);
+        }
+    }
+}
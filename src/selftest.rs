@@ -0,0 +1,186 @@
+//! The `self-test` subcommand, which runs each language's hello world program from languages.json
+//! in the same way as a real request, with every version of the language, to check that their
+//! images still work.
+//!
+//! Each result is written to stdout as a line of JSON, like `{"language":"zsh","result":"pass"}`,
+//! with a `version` as well for languages which name their versions. The result is `fail` if the
+//! program ran but didn't output what it should have, or `error` if it couldn't be run at all.
+//!
+//! It runs as a separate process from the server, with its own sandbox IDs if `$ATO_SELF_TEST_IDS`
+//! is set (see ids.rs), so the server can keep running.
+
+use crate::{
+    Error, Request, check,
+    client::Client,
+    close_frame, decode_message, ids, languages, queue, resolve_language, validate,
+    worker::{Done, FromWorker, Output, Worker},
+};
+use languages::HelloWorld;
+use nix::poll::{PollFd, PollFlags, poll};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::sync::Mutex;
+
+#[derive(Serialize)]
+struct Report<'a> {
+    language: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<&'a str>,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum Outcome {
+    Pass,
+    Fail {
+        stdout: String,
+        stderr: String,
+        timed_out: bool,
+        status_type: String,
+        status_value: i32,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// Test every version of the given languages (which may be aliases), or of every language which has
+/// a hello world program if none are given, running as many at once as `$ATO_MAX_SANDBOXES` and the
+/// sandbox IDs allow. Returns whether they all passed.
+pub fn run(ids: &[String]) -> bool {
+    let languages = languages::get();
+    let mut ids: Vec<&str> = ids
+        .iter()
+        .map(|id| languages.alias(id).map_or(id, |alias| &alias.id).as_str())
+        .collect();
+    if ids.is_empty() {
+        ids = languages
+            .iter()
+            .filter(|(_, language)| language.hello_world.is_some())
+            .map(|(id, _)| id.as_str())
+            .collect();
+        ids.sort();
+    }
+    let mut tests = vec![];
+    for id in ids {
+        match languages.get(id) {
+            Some(language) => {
+                tests.extend(language.all_versions(id).map(|version| (id, version.name)))
+            }
+            // reported as an error by test
+            None => tests.push((id, None)),
+        }
+    }
+    let todo = Mutex::new(tests.into_iter());
+    let all_passed = Mutex::new(true);
+    let threads = queue::max_running().min(ids::max_sandboxes().unwrap_or(usize::MAX));
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                loop {
+                    let Some((id, version)) = todo.lock().unwrap().next() else {
+                        break;
+                    };
+                    let outcome = test(id, version);
+                    if !matches!(outcome, Outcome::Pass) {
+                        *all_passed.lock().unwrap() = false;
+                    }
+                    let report = Report {
+                        language: id,
+                        version,
+                        outcome,
+                    };
+                    println!("{}", serde_json::to_string(&report).unwrap());
+                }
+            });
+        }
+    });
+    all_passed.into_inner().unwrap()
+}
+
+fn test(id: &str, version: Option<&str>) -> Outcome {
+    let Some(hello_world) = languages::get()
+        .get(id)
+        .and_then(|language| language.hello_world.as_ref())
+    else {
+        return Outcome::Error {
+            error: "no such language, or it has no hello world program".to_string(),
+        };
+    };
+    match run_one(id, version, hello_world) {
+        Ok((stdout, stderr, done)) => {
+            if !done.timed_out && stdout == hello_world.output.as_bytes() {
+                Outcome::Pass
+            } else {
+                Outcome::Fail {
+                    stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                    stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                    timed_out: done.timed_out,
+                    status_type: done.status_type,
+                    status_value: done.status_value,
+                }
+            }
+        }
+        Err(e) => Outcome::Error {
+            // the same message a client would get
+            error: close_frame(e).map_or_else(String::new, |frame| frame.reason.into_owned()),
+        },
+    }
+}
+
+/// Run the program through a worker, like the server does, and collect its stdout, stderr and Done
+/// message.
+fn run_one(
+    id: &str,
+    version: Option<&str>,
+    hello_world: &HelloWorld,
+) -> Result<(Vec<u8>, Vec<u8>, Done), Error> {
+    let bytes = |s: &String| ByteBuf::from(s.as_bytes());
+    let mut request = Request {
+        language: id.to_string(),
+        code: bytes(&hello_world.code),
+        custom_runner: None,
+        input: bytes(&hello_world.input),
+        arguments: hello_world.arguments.iter().map(bytes).collect(),
+        options: hello_world.options.iter().map(bytes).collect(),
+        timeout: None,
+        memory: None,
+        pids: None,
+        unprivileged_user: None,
+        deterministic: false,
+        version: version.map(str::to_string),
+    };
+    resolve_language(&mut request);
+    let (_, limits) = validate(&request, &Client::local())?;
+    // there are never more threads than slots
+    let slot = queue::try_start()
+        .ok_or_else(|| Error::InternalError("no sandbox slot is free".to_string()))?;
+    let mut worker = Worker::spawn(request, limits, slot)?;
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut done = None;
+    loop {
+        let mut poll_arg = [PollFd::new(worker.output_fd(), PollFlags::POLLIN)];
+        check!(poll(&mut poll_arg, -1), "error polling worker: {}");
+        let (messages, closed) = worker.read()?;
+        for message in messages {
+            match message {
+                FromWorker::Message(message) => match decode_message(&message)? {
+                    Output::Stdout(data) => stdout.extend(data.into_vec()),
+                    Output::Stderr(data) => stderr.extend(data.into_vec()),
+                    Output::Done(result) => done = Some(result),
+                },
+                FromWorker::Finished(result) => result?,
+            }
+        }
+        if closed {
+            break;
+        }
+    }
+    let done =
+        done.ok_or_else(|| Error::InternalError("worker finished without a result".into()))?;
+    Ok((stdout, stderr, done))
+}
@@ -0,0 +1,781 @@
+//! The main server thread's event loop, which handles every websocket connection at once, and only
+//! starts a worker thread (see worker.rs) when a request actually runs.
+
+use crate::{
+    ControlMessage, Error, HandshakeCallback, Limits, Request, StreamResponse, auth, client,
+    close_frame,
+    constants::*,
+    decode_message, encode_message, languages,
+    lifecycle::{Event, Lifecycle, Upgraded},
+    queue, ratelimit, resolve_language, send_bad_request, validate, versions, worker,
+    worker::{FromWorker, Output, Worker},
+    zygote,
+};
+use nix::{
+    errno::Errno,
+    sys::epoll::{
+        EpollCreateFlags, EpollEvent, EpollFlags, EpollOp, epoll_create1, epoll_ctl, epoll_wait,
+    },
+};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tungstenite as ws;
+use tungstenite::handshake::{MidHandshake, server::ServerHandshake};
+use tungstenite::protocol::WebSocketConfig;
+
+// epoll tokens which aren't connections
+const LISTENER: u64 = 0;
+const SIGNALS: u64 = 1;
+const SHUTDOWN: u64 = 2;
+const FIRST_CONNECTION: u64 = 16;
+/// set in the epoll token of a worker's output pipe, to tell it apart from its connection's socket
+const WORKER: u64 = 1 << 63;
+
+const MAX_EVENTS: usize = 256;
+/// maximum number of requests which can be waiting for a sandbox at once
+const MAX_QUEUED: usize = 1024;
+/// how often queued requests check whether they can start yet, as a sandbox could have finished in
+/// another process (i.e. an old version of the server which is still draining)
+const POLL_INTERVAL: isize = 100; // ms
+/// how long shutting down can take beyond the longest timeout, for sandboxes to be set up and cleaned
+/// up, and the last output to be sent
+const STOP_MARGIN: u64 = 30; // s
+/// how long a client has to finish the websocket handshake, so that connections which never do can't
+/// hold on to their fds
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// maximum number of connections at once, after which the listener is paused until one closes. While
+/// handshaking, each one takes two fds (see Session::Handshaking), and a running request takes more
+/// for its worker, so this keeps well within the default limit of 1024 fds
+const MAX_SESSIONS: usize = 256;
+/// how long the listener is paused for after running out of fds (or memory) to accept with
+const ACCEPT_PAUSE: Duration = Duration::from_millis(100);
+
+type Handshake = MidHandshake<ServerHandshake<TcpStream, HandshakeCallback>>;
+
+enum Session {
+    Handshaking {
+        handshake: Handshake,
+        // a copy of the connection so we can still respond if the handshake fails
+        raw: TcpStream,
+        client: Rc<Cell<Option<client::Client>>>,
+        // when the connection is closed if the handshake still hasn't finished
+        deadline: Instant,
+    },
+    Open(Open),
+    /// waiting to finish sending a close frame
+    Closing(ws::WebSocket<TcpStream>),
+}
+
+struct Open {
+    websocket: ws::WebSocket<TcpStream>,
+    client: client::Client,
+    request: RequestState,
+}
+
+enum RequestState {
+    Idle,
+    Queued {
+        request: Box<Request>,
+        limits: Limits,
+        // the last position in the queue that the client was told about
+        position: Option<usize>,
+    },
+    Running(Worker),
+}
+
+/// a version probe (see versions.rs) which is running
+struct Probing {
+    probe: versions::Probe,
+    worker: Worker,
+    stdout: Vec<u8>,
+    done: Option<worker::Done>,
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Serving,
+    /// this is an old version of the server, finishing off its connections after an upgrade
+    Draining,
+    ShuttingDown,
+}
+
+struct Server {
+    epoll: RawFd,
+    listener: Option<TcpListener>,
+    /// whether the listener has been taken out of epoll for now (see accept)
+    paused: bool,
+    /// when to start accepting again after running out of fds
+    retry_accept: Option<Instant>,
+    mode: Mode,
+    sessions: HashMap<u64, Session>,
+    next_token: u64,
+    // tokens of the sessions waiting to run a request, in order
+    queue: VecDeque<u64>,
+    // version probes which are waiting for a sandbox, which they only get when no client is waiting
+    probes: VecDeque<versions::Probe>,
+    // by the token of the worker's output pipe
+    probing: HashMap<u64, Probing>,
+}
+
+/// Serve websocket connections until the server shuts down.
+pub fn run(listener: TcpListener, mut lifecycle: Lifecycle) {
+    listener
+        .set_nonblocking(true)
+        .expect("error setting listener to non-blocking");
+    let epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).expect("error creating epoll");
+    let mut server = Server {
+        epoll,
+        listener: None,
+        paused: false,
+        retry_accept: None,
+        mode: Mode::Serving,
+        sessions: HashMap::new(),
+        next_token: FIRST_CONNECTION,
+        queue: VecDeque::new(),
+        probes: VecDeque::new(),
+        probing: HashMap::new(),
+    };
+    server.queue_probes();
+    server.watch(listener.as_raw_fd(), LISTENER, EpollFlags::EPOLLIN);
+    server.listener = Some(listener);
+    server.watch(lifecycle.signal_fd(), SIGNALS, EpollFlags::EPOLLIN);
+
+    let mut events = [EpollEvent::empty(); MAX_EVENTS];
+    while server.listener.is_some() || !server.sessions.is_empty() {
+        let count = match epoll_wait(epoll, &mut events, server.timeout()) {
+            Ok(count) => count,
+            Err(Errno::EINTR) => 0,
+            Err(e) => panic!("error waiting for events: {e}"),
+        };
+        for event in &events[..count] {
+            match event.data() {
+                LISTENER => server.accept(),
+                SIGNALS => match lifecycle.read_signal() {
+                    Some(Event::Shutdown) => server.shut_down(&mut lifecycle),
+                    Some(Event::Upgrade) => server.upgrade(&mut lifecycle),
+                    Some(Event::ReloadLanguages) => match languages::reload() {
+                        Ok(()) => server.queue_probes(),
+                        Err(e) => {
+                            eprintln!("error reloading languages, keeping the old ones: {e:?}")
+                        }
+                    },
+                    None => (),
+                },
+                SHUTDOWN => server.shut_down(&mut lifecycle),
+                token if token & WORKER != 0 && server.probing.contains_key(&(token & !WORKER)) => {
+                    server.handle_probe(token & !WORKER)
+                }
+                token if token & WORKER != 0 => server.handle_worker(token & !WORKER),
+                token => server.handle_session(token),
+            }
+        }
+        server.start_queued();
+        server.expire_handshakes();
+        server.resume_accepting();
+    }
+    eprintln!("all connections closed");
+    zygote::shut_down();
+    if !lifecycle.is_replaced() {
+        lifecycle.wait_for_old_versions();
+    }
+}
+
+/// whether accepting a connection failed because there weren't enough fds (or memory) for it, which
+/// won't change until something else is closed
+fn out_of_fds(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error().map(Errno::from_i32),
+        Some(Errno::EMFILE | Errno::ENFILE | Errno::ENOBUFS | Errno::ENOMEM)
+    )
+}
+
+/// send any messages which are waiting to be sent, for as long as the socket will take them
+fn flush(websocket: &mut ws::WebSocket<TcpStream>) -> Result<(), Error> {
+    match websocket.write_pending() {
+        Ok(()) => Ok(()),
+        Err(ws::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+        Err(ws::Error::ConnectionClosed | ws::Error::AlreadyClosed | ws::Error::Io(_)) => {
+            Err(Error::ClientWentAway)
+        }
+        Err(e) => Err(Error::InternalError(format!(
+            "error writing output message: {e}"
+        ))),
+    }
+}
+
+/// queue a message to be sent, sending as much as possible straight away
+fn send(websocket: &mut ws::WebSocket<TcpStream>, message: Vec<u8>) -> Result<(), Error> {
+    match websocket.write_message(ws::Message::Binary(message)) {
+        Ok(()) => Ok(()),
+        // the message stays queued, and is sent when the socket is writable again
+        Err(ws::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+        Err(ws::Error::ConnectionClosed | ws::Error::AlreadyClosed | ws::Error::Io(_)) => {
+            Err(Error::ClientWentAway)
+        }
+        Err(e) => Err(Error::InternalError(format!(
+            "error writing output message: {e}"
+        ))),
+    }
+}
+
+impl Server {
+    fn watch(&self, fd: RawFd, token: u64, flags: EpollFlags) {
+        let mut event = EpollEvent::new(flags, token);
+        epoll_ctl(self.epoll, EpollOp::EpollCtlAdd, fd, &mut event).expect("error watching fd");
+    }
+
+    fn unwatch(&self, fd: RawFd) {
+        // this only fails if the fd wasn't being watched, which doesn't matter
+        let _ = epoll_ctl(self.epoll, EpollOp::EpollCtlDel, fd, None);
+    }
+
+    /// how long to wait for events for: until the next queue poll, handshake deadline or retry of the
+    /// listener, whichever is first
+    fn timeout(&self) -> isize {
+        let mut timeout = if self.queue.is_empty() && self.probes.is_empty() {
+            -1
+        } else {
+            POLL_INTERVAL
+        };
+        let deadlines = self.sessions.values().filter_map(|session| match session {
+            Session::Handshaking { deadline, .. } => Some(*deadline),
+            _ => None,
+        });
+        if let Some(next) = deadlines.chain(self.retry_accept).min() {
+            // rounded up, so that it has passed by the time epoll returns
+            let left = next.saturating_duration_since(Instant::now());
+            let left = left.as_micros().div_ceil(1000) as isize;
+            if timeout < 0 || left < timeout {
+                timeout = left;
+            }
+        }
+        timeout
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let Some(listener) = &self.listener else {
+                return;
+            };
+            if self.sessions.len() >= MAX_SESSIONS {
+                // the listener is level-triggered, so it has to stop being watched, or epoll would
+                // keep returning straight away
+                eprintln!("too many connections; pausing the listener until one closes");
+                self.pause_accepting(None);
+                return;
+            }
+            let connection = match listener.accept() {
+                Ok((connection, _)) => connection,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if out_of_fds(&e) => {
+                    eprintln!("error accepting connection: {e}; pausing the listener");
+                    self.pause_accepting(Some(Instant::now() + ACCEPT_PAUSE));
+                    return;
+                }
+                Err(e) => {
+                    // the client may have given up already, which is no reason to stop
+                    eprintln!("error accepting connection: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = self.start_session(connection) {
+                eprintln!("error setting up connection: {e}");
+                if out_of_fds(&e) {
+                    self.pause_accepting(Some(Instant::now() + ACCEPT_PAUSE));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// stop watching the listener, until there is room for another session and (if given) the time to
+    /// retry has come
+    fn pause_accepting(&mut self, retry: Option<Instant>) {
+        if let Some(listener) = &self.listener {
+            self.unwatch(listener.as_raw_fd());
+        }
+        self.paused = true;
+        self.retry_accept = retry;
+    }
+
+    fn resume_accepting(&mut self) {
+        if !self.paused || self.sessions.len() >= MAX_SESSIONS {
+            return;
+        }
+        if self
+            .retry_accept
+            .is_some_and(|retry| Instant::now() < retry)
+        {
+            return;
+        }
+        self.paused = false;
+        self.retry_accept = None;
+        // connections which arrived in the meantime make the listener readable straight away
+        if let Some(listener) = &self.listener {
+            self.watch(listener.as_raw_fd(), LISTENER, EpollFlags::EPOLLIN);
+        }
+    }
+
+    /// close the connections of clients which have taken too long over the handshake
+    fn expire_handshakes(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                matches!(session, Session::Handshaking { deadline, .. } if *deadline <= now)
+            })
+            .map(|(&token, _)| token)
+            .collect();
+        for token in expired {
+            if let Some(Session::Handshaking { raw, .. }) = self.sessions.remove(&token) {
+                self.unwatch(raw.as_raw_fd());
+            }
+        }
+    }
+
+    fn start_session(&mut self, connection: TcpStream) -> std::io::Result<()> {
+        connection.set_nonblocking(true)?;
+        let peer = connection.peer_addr()?.ip();
+        let raw = connection.try_clone()?;
+        let token = self.next_token;
+        self.next_token += 1;
+        // edge-triggered, so we are only told about sockets which have something new to do, but
+        // must then read and write them until they would block
+        self.watch(
+            connection.as_raw_fd(),
+            token,
+            EpollFlags::EPOLLIN
+                | EpollFlags::EPOLLOUT
+                | EpollFlags::EPOLLRDHUP
+                | EpollFlags::EPOLLET,
+        );
+        let client = Rc::new(Cell::new(None));
+        let callback = HandshakeCallback {
+            peer,
+            client: client.clone(),
+        };
+        let mut config = WebSocketConfig::default();
+        config.max_message_size = Some(MAX_REQUEST_SIZE);
+        let result = tungstenite::accept_hdr_with_config(connection, callback, Some(config));
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        self.continue_handshake(token, result, raw, client, deadline);
+        Ok(())
+    }
+
+    fn continue_handshake(
+        &mut self,
+        token: u64,
+        result: Result<
+            ws::WebSocket<TcpStream>,
+            ws::HandshakeError<ServerHandshake<TcpStream, HandshakeCallback>>,
+        >,
+        raw: TcpStream,
+        client: Rc<Cell<Option<client::Client>>>,
+        deadline: Instant,
+    ) {
+        match result {
+            Ok(websocket) => {
+                let client = client
+                    .take()
+                    .expect("handshake succeeded without identifying the client");
+                let open = Open {
+                    websocket,
+                    client,
+                    request: RequestState::Idle,
+                };
+                self.sessions.insert(token, Session::Open(open));
+                // the client may have sent its request along with the handshake, in which case the
+                // socket won't be readable again
+                self.handle_session(token);
+            }
+            Err(ws::HandshakeError::Interrupted(handshake)) => {
+                let handshaking = Session::Handshaking {
+                    handshake,
+                    raw,
+                    client,
+                    deadline,
+                };
+                self.sessions.insert(token, handshaking);
+            }
+            Err(ws::HandshakeError::Failure(ws::Error::Http(_))) => {
+                // handle_headers rejected the request, and its response has already been sent
+                self.unwatch(raw.as_raw_fd());
+            }
+            Err(ws::HandshakeError::Failure(e)) => {
+                // the request was not a valid websocket handshake
+                self.unwatch(raw.as_raw_fd());
+                send_bad_request(raw, e);
+            }
+        }
+    }
+
+    fn handle_session(&mut self, token: u64) {
+        match self.sessions.remove(&token) {
+            None => (),
+            Some(Session::Handshaking {
+                handshake,
+                raw,
+                client,
+                deadline,
+            }) => self.continue_handshake(token, handshake.handshake(), raw, client, deadline),
+            Some(Session::Open(mut open)) => {
+                let result = self.handle_messages(token, &mut open);
+                self.finish(token, open, result);
+            }
+            Some(Session::Closing(mut websocket)) => match websocket.write_pending() {
+                Err(ws::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                    self.sessions.insert(token, Session::Closing(websocket));
+                }
+                // either the close frame has been sent, or it can't be
+                _ => self.unwatch(websocket.get_ref().as_raw_fd()),
+            },
+        }
+    }
+
+    /// put the session back, or close it if there was an error
+    fn finish(&mut self, token: u64, mut open: Open, result: Result<(), Error>) {
+        let error = match result.and_then(|()| flush(&mut open.websocket)) {
+            Ok(()) => {
+                self.sessions.insert(token, Session::Open(open));
+                return;
+            }
+            Err(error) => error,
+        };
+        // dropping the worker (if there is one) kills the sandbox
+        drop(open.request);
+        self.queue.retain(|&t| t != token);
+        let mut websocket = open.websocket;
+        let fd = websocket.get_ref().as_raw_fd();
+        let Some(frame) = close_frame(error) else {
+            self.unwatch(fd);
+            return;
+        };
+        match websocket.close(Some(frame)) {
+            Err(ws::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                self.sessions.insert(token, Session::Closing(websocket));
+            }
+            // either the close frame has been sent, or it can't be
+            _ => self.unwatch(fd),
+        }
+    }
+
+    fn handle_messages(&mut self, token: u64, open: &mut Open) -> Result<(), Error> {
+        loop {
+            let message = match open.websocket.read_message() {
+                Ok(ws::Message::Binary(b)) => b,
+                // tungstenite answers pings by itself
+                Ok(ws::Message::Ping(_) | ws::Message::Pong(_)) => continue,
+                Ok(ws::Message::Close(_)) => return Err(Error::ClientWentAway),
+                Ok(_) => return Err(Error::UnsupportedData),
+                Err(ws::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ws::Error::ConnectionClosed | ws::Error::AlreadyClosed | ws::Error::Io(_)) => {
+                    return Err(Error::ClientWentAway);
+                }
+                Err(ws::Error::Capacity(ws::error::CapacityError::MessageTooLong {
+                    size, ..
+                })) => return Err(Error::TooLarge(size)),
+                Err(e) => return Err(Error::InternalError(format!("error reading request: {e}"))),
+            };
+            match &mut open.request {
+                RequestState::Idle => {
+                    let mut request: Request = decode_message(&message)?;
+                    let warning = resolve_language(&mut request);
+                    let (_, limits) = validate(&request, &open.client)?;
+                    if self.mode != Mode::Serving {
+                        // it would never start
+                        return Err(Error::ShuttingDown);
+                    }
+                    // checked first, so that requests which are turned away aren't charged for
+                    if self.queue.len() >= MAX_QUEUED {
+                        return Err(Error::TryAgainLater(
+                            "too many requests are queued".to_string(),
+                        ));
+                    }
+                    ratelimit::consume(&open.client)?;
+                    open.client.limits().consume_quota()?;
+                    if let Some(warning) = warning {
+                        send(
+                            &mut open.websocket,
+                            encode_message(StreamResponse::Warning(warning))?,
+                        )?;
+                    }
+                    open.request = RequestState::Queued {
+                        request: Box::new(request),
+                        limits,
+                        position: None,
+                    };
+                    self.queue.push_back(token);
+                }
+                RequestState::Queued { .. } => match decode_message(&message)? {
+                    ControlMessage::Kill => {
+                        // the client killed the request before it started
+                        self.queue.retain(|&t| t != token);
+                        open.request = RequestState::Idle;
+                    }
+                },
+                RequestState::Running(worker) => worker.control(decode_message(&message)?)?,
+            }
+        }
+    }
+
+    fn handle_worker(&mut self, token: u64) {
+        let Some(Session::Open(mut open)) = self.sessions.remove(&token) else {
+            return;
+        };
+        let result = self.handle_worker_messages(&mut open);
+        self.finish(token, open, result);
+    }
+
+    fn handle_worker_messages(&mut self, open: &mut Open) -> Result<(), Error> {
+        let RequestState::Running(worker) = &mut open.request else {
+            return Ok(());
+        };
+        let (messages, closed) = worker.read()?;
+        for message in messages {
+            match message {
+                FromWorker::Message(message) => send(&mut open.websocket, message.into_vec())?,
+                FromWorker::Finished(result) => {
+                    self.unwatch(worker.output_fd());
+                    open.request = RequestState::Idle;
+                    result?;
+                    if self.mode != Mode::Serving {
+                        return Err(Error::ShuttingDown);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        if closed {
+            self.unwatch(worker.output_fd());
+            open.request = RequestState::Idle;
+            return Err(Error::InternalError(
+                "worker thread exited unexpectedly".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// start as many queued requests as there is capacity for, and tell the rest where they are
+    fn start_queued(&mut self) {
+        // when shutting down, the queue has been emptied, but don't start anything in any case. An
+        // old version which is draining still starts the requests it had already queued, so that
+        // they don't lose their place
+        let starting = self.mode != Mode::ShuttingDown;
+        while let Some(&token) = self.queue.front().filter(|_| starting) {
+            let Some(slot) = queue::try_start() else {
+                break;
+            };
+            self.queue.pop_front();
+            let Some(Session::Open(mut open)) = self.sessions.remove(&token) else {
+                continue;
+            };
+            let result = self.start(token, &mut open, slot);
+            self.finish(token, open, result);
+        }
+        // version probes only run if there is a sandbox which no client is waiting for
+        while self.mode == Mode::Serving && self.queue.is_empty() {
+            let Some(probe) = self.probes.pop_front() else {
+                break;
+            };
+            let Some(slot) = queue::try_start() else {
+                self.probes.push_front(probe);
+                break;
+            };
+            if let Err(e) = self.start_probe(probe, slot) {
+                eprintln!("error starting version probe: {e:?}");
+            }
+        }
+        for position in 0..self.queue.len() {
+            let token = self.queue[position];
+            let Some(Session::Open(mut open)) = self.sessions.remove(&token) else {
+                continue;
+            };
+            let result = match &mut open.request {
+                RequestState::Queued {
+                    position: last_position,
+                    ..
+                } if *last_position != Some(position) => {
+                    *last_position = Some(position);
+                    encode_message(StreamResponse::Queued { position })
+                        .and_then(|message| send(&mut open.websocket, message))
+                }
+                _ => Ok(()),
+            };
+            self.finish(token, open, result);
+        }
+    }
+
+    fn start(&mut self, token: u64, open: &mut Open, slot: queue::Slot) -> Result<(), Error> {
+        let RequestState::Queued {
+            request, limits, ..
+        } = std::mem::replace(&mut open.request, RequestState::Idle)
+        else {
+            return Ok(());
+        };
+        let worker = Worker::spawn(*request, limits, slot)?;
+        self.watch(
+            worker.output_fd(),
+            token | WORKER,
+            EpollFlags::EPOLLIN | EpollFlags::EPOLLET,
+        );
+        open.request = RequestState::Running(worker);
+        Ok(())
+    }
+
+    /// queue probes for every version of a language which hasn't been probed since its image changed,
+    /// after publishing the languages, which have just been (re)loaded, with what is known already
+    fn queue_probes(&mut self) {
+        versions::publish();
+        for probe in versions::stale() {
+            let running = self.probing.values().any(|probing| probing.probe == probe);
+            if !running && !self.probes.contains(&probe) {
+                self.probes.push_back(probe);
+            }
+        }
+    }
+
+    fn start_probe(&mut self, probe: versions::Probe, slot: queue::Slot) -> Result<(), Error> {
+        let (request, limits) = probe.request()?;
+        let worker = Worker::spawn(request, limits, slot)?;
+        let token = self.next_token;
+        self.next_token += 1;
+        self.watch(
+            worker.output_fd(),
+            token | WORKER,
+            EpollFlags::EPOLLIN | EpollFlags::EPOLLET,
+        );
+        let probing = Probing {
+            probe,
+            worker,
+            stdout: vec![],
+            done: None,
+        };
+        self.probing.insert(token, probing);
+        Ok(())
+    }
+
+    fn handle_probe(&mut self, token: u64) {
+        let Some(mut probing) = self.probing.remove(&token) else {
+            return;
+        };
+        let error = match self.handle_probe_messages(&mut probing) {
+            Ok(false) => {
+                self.probing.insert(token, probing);
+                return;
+            }
+            Ok(true) => match probing.done.take() {
+                Some(done)
+                    if !done.timed_out
+                        && done.status_type == "exited"
+                        && done.status_value == 0 =>
+                {
+                    probing.probe.finish(&probing.stdout);
+                    return;
+                }
+                Some(done) => format!("it {} with {}", done.status_type, done.status_value),
+                None => "it didn't finish".to_string(),
+            },
+            Err(e) => format!("{e:?}"),
+        };
+        eprintln!("version probe for {} failed: {error}", probing.probe.name());
+    }
+
+    /// read the probe's output, returning true once it has finished
+    fn handle_probe_messages(&mut self, probing: &mut Probing) -> Result<bool, Error> {
+        let (messages, closed) = probing.worker.read()?;
+        for message in messages {
+            match message {
+                FromWorker::Message(message) => match decode_message(&message)? {
+                    Output::Stdout(data) => probing.stdout.extend(data.into_vec()),
+                    Output::Stderr(_) => (),
+                    Output::Done(done) => probing.done = Some(done),
+                },
+                FromWorker::Finished(result) => {
+                    self.unwatch(probing.worker.output_fd());
+                    result?;
+                    return Ok(true);
+                }
+            }
+        }
+        if closed {
+            self.unwatch(probing.worker.output_fd());
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Stop accepting connections, and close each connection once it isn't running a request.
+    fn shut_down(&mut self, lifecycle: &mut Lifecycle) {
+        if self.mode == Mode::ShuttingDown {
+            return;
+        }
+        eprintln!("shutting down; waiting for running requests to finish");
+        self.mode = Mode::ShuttingDown;
+        // nothing new starts from now on, so the longest a running request can take is the longest
+        // timeout any client can ask for
+        let longest = languages::get()
+            .iter()
+            .map(|(_, language)| auth::max_limits(&language.limits).0)
+            .max()
+            .unwrap_or(DEFAULT_MAX_TIMEOUT);
+        lifecycle.start_shutdown(Duration::from_secs(longest as u64 + STOP_MARGIN));
+        self.unwatch(lifecycle.shutdown_fd());
+        if let Some(listener) = self.listener.take() {
+            self.unwatch(listener.as_raw_fd());
+        }
+        self.close_idle();
+    }
+
+    /// Close every connection which isn't running a request, and (when shutting down) those whose
+    /// requests are queued.
+    fn close_idle(&mut self) {
+        let idle: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| match session {
+                Session::Handshaking { .. } => true,
+                Session::Open(open) => match open.request {
+                    RequestState::Idle => true,
+                    RequestState::Queued { .. } => self.mode == Mode::ShuttingDown,
+                    RequestState::Running(_) => false,
+                },
+                Session::Closing(_) => false,
+            })
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            match self.sessions.remove(&token) {
+                Some(Session::Open(open)) => self.finish(token, open, Err(Error::ShuttingDown)),
+                Some(Session::Handshaking { raw, .. }) => self.unwatch(raw.as_raw_fd()),
+                _ => (),
+            }
+        }
+    }
+
+    fn upgrade(&mut self, lifecycle: &mut Lifecycle) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+        match lifecycle.upgrade(listener) {
+            Upgraded::Failed => (),
+            Upgraded::Draining => {
+                eprintln!("finishing off connections for the new version of the server");
+                self.mode = Mode::Draining;
+                // the new version has its own copy of the listener, so make sure we don't get its
+                // events
+                if let Some(listener) = self.listener.take() {
+                    self.unwatch(listener.as_raw_fd());
+                }
+                self.watch(lifecycle.shutdown_fd(), SHUTDOWN, EpollFlags::EPOLLIN);
+                // clients which aren't waiting for anything can reconnect to the new version
+                self.close_idle();
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use crate::{auth, lifecycle, queue, ratelimit};
+use nix::{
+    errno::Errno,
+    sys::{
+        memfd::{MemFdCreateFlag, memfd_create},
+        mman::{MapFlags, ProtFlags, mmap},
+        signal::kill,
+        stat::fstat,
+        uio::pread,
+    },
+    unistd::{Pid, close, ftruncate, getpid},
+};
+use std::cell::UnsafeCell;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::os::fd::RawFd;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering::*};
+
+/// Must be incremented whenever the layout of Shared changes, so that a new version of the server
+/// doesn't try to use the shared memory of an old one after an upgrade.
+const LAYOUT_VERSION: u64 = 2;
+
+/// State which is shared between the current server process and any old versions of it which are
+/// still draining after an upgrade.
+///
+/// It lives in a shared memory file which is created before any forking happens, so it must consist
+/// only of types for which all-zero bytes are a valid (and sensible) initial value, i.e. atomics and
+/// arrays of atomics.
+// repr(C) keeps layout_version at the start, so it can be checked before mapping the rest
+#[repr(C)]
+pub struct Shared {
+    layout_version: AtomicU64,
+    pub queue: queue::State,
+    pub rate_limits: ratelimit::State,
+    pub quotas: auth::QuotaState,
+}
+
+static SHARED: OnceLock<(RawFd, &'static Shared)> = OnceLock::new();
+
+fn create_file() -> RawFd {
+    let fd = memfd_create(c"ATO shared state", MemFdCreateFlag::MFD_CLOEXEC)
+        .expect("error creating shared memory");
+    // the kernel guarantees the new space is zero-filled, which is a valid value for Shared (see
+    // above)
+    ftruncate(fd, std::mem::size_of::<Shared>() as i64).expect("error resizing shared memory");
+    fd
+}
+
+/// check whether shared memory passed on by the previous version of the server has the same layout
+fn is_compatible(fd: RawFd) -> bool {
+    let mut version = [0; 8];
+    fstat(fd).is_ok_and(|stat| stat.st_size == std::mem::size_of::<Shared>() as i64)
+        && pread(fd, &mut version, 0).is_ok_and(|n| n == version.len())
+        && u64::from_ne_bytes(version) == LAYOUT_VERSION
+}
+
+/// Create the shared memory mapping, or reuse the one passed on by the previous version of the
+/// server after an upgrade. This must be called before any processes are forked, or they won't share
+/// the same memory.
+pub fn init() {
+    let fd = match lifecycle::take_inherited_fd(lifecycle::SHARED_FD_VAR) {
+        Some(fd) if is_compatible(fd) => fd,
+        Some(fd) => {
+            eprintln!("shared state layout has changed; not sharing it with the previous version");
+            let _ = close(fd);
+            create_file()
+        }
+        None => create_file(),
+    };
+    let size = NonZeroUsize::new(std::mem::size_of::<Shared>()).unwrap();
+    // this is safe because we don't ask for a specific address, and the file is the right size
+    let ptr = unsafe {
+        mmap(
+            None,
+            size,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_SHARED,
+            fd,
+            0,
+        )
+    }
+    .expect("error mapping shared memory");
+    // the mapping is never unmapped, so the reference is valid for the rest of the program
+    let shared = unsafe { &*(ptr as *const Shared) };
+    shared.layout_version.store(LAYOUT_VERSION, SeqCst);
+    if SHARED.set((fd, shared)).is_err() {
+        panic!("shared memory initialised twice");
+    }
+}
+
+fn get_both() -> &'static (RawFd, &'static Shared) {
+    SHARED.get().expect("shared memory not initialised")
+}
+
+pub fn get() -> &'static Shared {
+    get_both().1
+}
+
+/// the file descriptor of the shared memory, for passing on to a new version of the server
+pub fn fd() -> RawFd {
+    get_both().0
+}
+
+/// check whether a process still exists, for cleaning up after processes which died unexpectedly
+pub fn is_alive(pid: i32) -> bool {
+    kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH)
+}
+
+/// hash a key for use in a Table. The hash is the same in every process, and is never 0
+pub fn hash_key(key: impl Hash) -> u64 {
+    // DefaultHasher::new always uses the same keys, unlike RandomState
+    let mut hasher = std::hash::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+/// how many neighbouring entries are tried before giving up on finding space in a Table
+const MAX_PROBES: usize = 16;
+
+struct Entry<V> {
+    // pid of the process currently using this entry, or 0 if it is unlocked
+    lock: AtomicI32,
+    // hash of the entry's key, or 0 if it is empty
+    key: UnsafeCell<u64>,
+    value: UnsafeCell<V>,
+}
+
+// this is safe because key and value are only ever accessed while holding the lock
+unsafe impl<V: Send> Sync for Entry<V> {}
+
+impl<V> Entry<V> {
+    fn lock(&self) -> EntryGuard<'_, V> {
+        let pid = getpid().as_raw();
+        loop {
+            match self.lock.compare_exchange(0, pid, Acquire, Relaxed) {
+                Ok(_) => break,
+                Err(holder) if !is_alive(holder) => {
+                    // the holder died while it had the lock, so take over from it.
+                    // If this fails, someone else has already done so
+                    if self
+                        .lock
+                        .compare_exchange(holder, pid, Acquire, Relaxed)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+                Err(_) => std::thread::yield_now(),
+            }
+        }
+        EntryGuard(self)
+    }
+}
+
+struct EntryGuard<'a, V>(&'a Entry<V>);
+
+impl<V> Drop for EntryGuard<'_, V> {
+    fn drop(&mut self) {
+        self.0.lock.store(0, Release);
+    }
+}
+
+/// A fixed-size hash table in shared memory.
+///
+/// Values must be valid when all-zero, and an all-zero value must mean the same as a missing entry.
+pub struct Table<V, const N: usize> {
+    entries: [Entry<V>; N],
+}
+
+impl<V, const N: usize> Table<V, N> {
+    /// Call `f` on the value for `key` (from hash_key), while nobody else can access it.
+    ///
+    /// If the key isn't in the table yet, it gets an all-zero value. To make space, entries for other
+    /// keys may be evicted if `is_stale` returns true for their value. If there isn't any space,
+    /// `f` is not called and None is returned.
+    pub fn with<R>(
+        &self,
+        key: u64,
+        is_stale: impl Fn(&V) -> bool,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> Option<R> {
+        for i in 0..MAX_PROBES {
+            let entry = &self.entries[(key as usize).wrapping_add(i) % N];
+            let _guard = entry.lock();
+            // this is safe because we hold the lock
+            let (entry_key, value) = unsafe { (&mut *entry.key.get(), &mut *entry.value.get()) };
+            if *entry_key == key {
+                return Some(f(value));
+            } else if *entry_key == 0 || is_stale(value) {
+                *entry_key = key;
+                // this is safe because all-zero is a valid value (see above)
+                *value = unsafe { std::mem::zeroed() };
+                return Some(f(value));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// the pid of a process which has exited, for slots and locks held by a process which died
+    pub fn dead_pid() -> i32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id() as i32
+    }
+
+    fn table() -> Table<u64, 4> {
+        // this is safe because all-zero is a valid Table (see above)
+        unsafe { std::mem::zeroed() }
+    }
+
+    fn get(table: &Table<u64, 4>, key: u64) -> Option<u64> {
+        table.with(key, |_| false, |value| *value)
+    }
+
+    #[test]
+    fn keys_kept_apart() {
+        let table = table();
+        for key in 1..=4 {
+            assert_eq!(
+                table.with(key, |_| false, |value| *value = key * 10),
+                Some(())
+            );
+        }
+        for key in 1..=4 {
+            assert_eq!(get(&table, key), Some(key * 10));
+        }
+        assert_ne!(hash_key(0), 0);
+        assert_eq!(hash_key("a"), hash_key("a"));
+    }
+
+    #[test]
+    fn eviction() {
+        let table = table();
+        for key in 1..=4 {
+            table.with(key, |_| false, |value| *value = key * 10);
+        }
+        // full, and nothing can be evicted
+        assert_eq!(get(&table, 5), None);
+        assert_eq!(table.with(5, |&value| value == 20, |value| *value), Some(0));
+        assert_eq!(get(&table, 2), None);
+        for key in [1, 3, 4] {
+            assert_eq!(get(&table, key), Some(key * 10));
+        }
+    }
+
+    #[test]
+    fn lock_taken_from_dead_process() {
+        let table = table();
+        table.with(1, |_| false, |value| *value = 10);
+        for entry in &table.entries {
+            entry.lock.store(dead_pid(), SeqCst);
+        }
+        assert_eq!(get(&table, 1), Some(10));
+        assert!(
+            table
+                .entries
+                .iter()
+                .any(|entry| entry.lock.load(SeqCst) == 0)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Detection of the exact version of each language's compiler or interpreter, for languages which
+//! have a `version_probe` in languages.json.
+//!
+//! When the server starts, and after the languages are reloaded, the probe is run for each version
+//! of each language whose image has changed since it was last probed, as told by the image's digest
+//! recorded when it was installed (see `digest_file` in languages.rs). It runs in a sandbox like
+//! any other request, started by the event loop (see server.rs) whenever there is a sandbox free
+//! that no client is waiting for. What it prints is reported in the Done message of requests for
+//! that version of the language.
+//!
+//! If `$ATO_VERSIONS_FILE` is set, the results are also kept in that file, so that they survive
+//! restarts. If `$ATO_CATALOGUE_FILE` is set, the languages file is written there as it was loaded,
+//! with what was detected added to each language and each of its `versions` as `detected_version`,
+//! to be served to the frontend in place of the original.
+
+use crate::{Error, Limits, Request, client::Client, languages, validate};
+use languages::{Language, Version};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+struct Detected {
+    version: Option<String>,
+    detected: String,
+    // the probe only needs to be run again if one of these changes
+    image: String,
+    probe: String,
+    /// None if it wasn't recorded, in which case the probe is run again every time
+    digest: Option<String>,
+}
+
+// by language ID
+static DETECTED: Mutex<BTreeMap<String, Vec<Detected>>> = Mutex::new(BTreeMap::new());
+
+fn path(name: &str) -> Option<String> {
+    match std::env::var(name) {
+        Ok(path) => Some(path),
+        Err(std::env::VarError::NotPresent) => None,
+        Err(std::env::VarError::NotUnicode(_)) => panic!("${name} is invalid Unicode"),
+    }
+}
+
+/// Read the versions which were detected before the server was last restarted.
+pub fn load() {
+    let Some(path) = path("ATO_VERSIONS_FILE") else {
+        return;
+    };
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return,
+        Err(e) => {
+            eprintln!("error reading {path}, so all versions will be probed again: {e}");
+            return;
+        }
+    };
+    match serde_json::from_slice(&data) {
+        Ok(detected) => *DETECTED.lock().unwrap() = detected,
+        Err(e) => eprintln!("{path} is invalid, so all versions will be probed again: {e}"),
+    }
+}
+
+fn save(detected: &BTreeMap<String, Vec<Detected>>) -> Result<(), String> {
+    let Some(path) = path("ATO_VERSIONS_FILE") else {
+        return Ok(());
+    };
+    write(&path, &serde_json::to_vec_pretty(detected).unwrap())
+}
+
+/// Write the file at `path` by writing another file and renaming it, so that nothing ever reads
+/// half of it.
+fn write(path: &str, data: &[u8]) -> Result<(), String> {
+    let temporary = format!("{path}.new");
+    std::fs::write(&temporary, data).map_err(|e| format!("error writing {temporary}: {e}"))?;
+    std::fs::rename(&temporary, path).map_err(|e| format!("error replacing {path}: {e}"))
+}
+
+/// Write the current languages, with the versions detected for them so far, to
+/// `$ATO_CATALOGUE_FILE`. Called whenever either changes, from the network-facing process, which
+/// owns the file.
+pub fn publish() {
+    let detected = DETECTED.lock().unwrap();
+    if let Err(e) = publish_locked(&detected) {
+        eprintln!("{e}");
+    }
+}
+
+fn publish_locked(detected: &BTreeMap<String, Vec<Detected>>) -> Result<(), String> {
+    let Some(path) = path("ATO_CATALOGUE_FILE") else {
+        return Ok(());
+    };
+    let languages = languages::get();
+    let mut catalogue = languages.catalogue().clone();
+    for (id, entry) in &mut catalogue {
+        let Some(language) = languages.get(id) else {
+            continue;
+        };
+        for version in language.all_versions(id) {
+            let Some(found) = find(detected, id, language, &version) else {
+                continue;
+            };
+            // the default version is described by the language's own entry
+            let target = match version.name {
+                Some(name) if Some(name) != language.default_version.as_deref() => entry
+                    .get_mut("versions")
+                    .and_then(|versions| versions.get_mut(name)),
+                _ => Some(&mut *entry),
+            };
+            if let Some(serde_json::Value::Object(target)) = target {
+                target.insert("detected_version".into(), found.detected.clone().into());
+            }
+        }
+    }
+    write(&path, &serde_json::to_vec(&catalogue).unwrap())
+}
+
+/// the digest of the installed image, if it was recorded
+fn image_digest(version: &Version) -> Option<String> {
+    let digest = std::fs::read_to_string(version.digest_file()).ok()?;
+    Some(digest.trim().to_string()).filter(|digest| !digest.is_empty())
+}
+
+/// What was last detected for a version of a language, with its current image and probe, even if
+/// the image has been updated since.
+fn find<'a>(
+    detected: &'a BTreeMap<String, Vec<Detected>>,
+    id: &str,
+    language: &Language,
+    version: &Version,
+) -> Option<&'a Detected> {
+    let probe = language.version_probe.as_ref()?;
+    detected.get(id)?.iter().find(|entry| {
+        entry.version.as_deref() == version.name
+            && entry.image == version.image
+            && &entry.probe == probe
+    })
+}
+
+/// The detected version of the compiler or interpreter for a version of a language (as named in
+/// the request), if it has been probed.
+pub fn get(id: &str, requested: Option<&str>) -> Option<String> {
+    let language = languages::get().get(id)?;
+    let version = language.version(id, requested)?;
+    let detected = DETECTED.lock().unwrap();
+    find(&detected, id, language, &version).map(|entry| entry.detected.clone())
+}
+
+/// One version of a language which needs to be probed.
+#[derive(PartialEq)]
+pub struct Probe {
+    language: String,
+    version: Option<String>,
+    image: String,
+    probe: String,
+    digest: Option<String>,
+}
+
+/// The versions of languages which haven't been probed since their image was last updated.
+pub fn stale() -> Vec<Probe> {
+    let detected = DETECTED.lock().unwrap();
+    let mut stale = vec![];
+    for (id, language) in languages::get().iter() {
+        let Some(probe) = &language.version_probe else {
+            continue;
+        };
+        for version in language.all_versions(id) {
+            let probe = Probe {
+                language: id.clone(),
+                version: version.name.map(String::from),
+                image: version.image.to_string(),
+                probe: probe.clone(),
+                digest: image_digest(&version),
+            };
+            let entries = detected.get(id).map_or(&[][..], |entries| &entries[..]);
+            if !entries.iter().any(|entry| probe.is_for(entry)) {
+                stale.push(probe);
+            }
+        }
+    }
+    stale
+}
+
+impl Probe {
+    fn is_for(&self, entry: &Detected) -> bool {
+        entry.version == self.version
+            && entry.image == self.image
+            && entry.probe == self.probe
+            && self.digest.is_some()
+            && entry.digest == self.digest
+    }
+
+    /// for log messages
+    pub fn name(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{} version {version}", self.language),
+            None => self.language.clone(),
+        }
+    }
+
+    /// The request which runs the probe.
+    pub fn request(&self) -> Result<(Request, Limits), Error> {
+        let request = Request {
+            language: self.language.clone(),
+            code: ByteBuf::new(),
+            custom_runner: Some(ByteBuf::from(self.probe.as_bytes())),
+            input: ByteBuf::new(),
+            arguments: vec![],
+            options: vec![],
+            timeout: None,
+            memory: None,
+            pids: None,
+            unprivileged_user: None,
+            deterministic: false,
+            version: self.version.clone(),
+        };
+        let (_, limits) = validate(&request, &Client::local())?;
+        Ok((request, limits))
+    }
+
+    /// Record what the probe printed, without any surrounding whitespace, as the version.
+    pub fn finish(self, stdout: &[u8]) {
+        let output = String::from_utf8_lossy(stdout).trim().to_string();
+        if output.is_empty() {
+            eprintln!("version probe for {} printed nothing", self.name());
+            return;
+        }
+        let mut detected = DETECTED.lock().unwrap();
+        let entries = detected.entry(self.language).or_default();
+        entries.retain(|entry| entry.version != self.version);
+        entries.push(Detected {
+            version: self.version,
+            detected: output,
+            image: self.image,
+            probe: self.probe,
+            digest: self.digest,
+        });
+        for result in [save(&detected), publish_locked(&detected)] {
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
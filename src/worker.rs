@@ -0,0 +1,219 @@
+//! Worker threads, which are started by the main server thread to run one request each.
+//!
+//! A worker talks to the client through the event loop, using two pipes: it sends length-prefixed
+//! messages (already encoded for the client) down one, and receives control messages from the
+//! client through the other.
+
+use crate::{
+    ControlMessage, Error, Limits, Request, StreamResponse, check, decode_message, encode_message,
+    queue,
+    sandbox::{ControlSource, OutputSink, invoke},
+};
+use nix::{
+    fcntl::{FcntlArg, OFlag, fcntl},
+    unistd::pipe2,
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+
+/// A message from a worker thread to the event loop.
+#[derive(Serialize, Deserialize)]
+pub enum FromWorker {
+    /// an encoded message to be sent on to the client as it is
+    Message(ByteBuf),
+    /// the request has finished, successfully or otherwise
+    Finished(Result<(), Error>),
+}
+
+/// The parts of the messages for the client which matter when the server runs a request for
+/// itself (see selftest.rs and versions.rs).
+#[derive(Deserialize)]
+pub enum Output {
+    Stdout(ByteBuf),
+    Stderr(ByteBuf),
+    Done(Done),
+}
+
+#[derive(Deserialize)]
+pub struct Done {
+    pub timed_out: bool,
+    pub status_type: String,
+    pub status_value: i32,
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// The worker's end of its connection to the client, via the event loop.
+struct Connection {
+    output: File,
+}
+
+/// The control messages from the client, via the event loop.
+struct Control {
+    control: File,
+}
+
+impl Connection {
+    fn send(&mut self, message: FromWorker) -> Result<(), Error> {
+        let message = encode_message(message)?;
+        match self.output.write_all(&frame(&message)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => Err(Error::ClientWentAway),
+            Err(e) => Err(Error::InternalError(format!(
+                "error writing output message: {e}"
+            ))),
+        }
+    }
+}
+
+impl OutputSink for Connection {
+    fn output(&mut self, message: StreamResponse) -> Result<(), Error> {
+        let message = encode_message(message)?;
+        self.send(FromWorker::Message(ByteBuf::from(message)))
+    }
+}
+
+impl ControlSource for Control {
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.control.as_raw_fd())
+    }
+
+    fn read(&mut self) -> Result<ControlMessage, Error> {
+        let mut length = [0; 4];
+        match self.control.read_exact(&mut length) {
+            Ok(()) => (),
+            // the event loop closes the pipe when the client goes away
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(Error::ClientWentAway),
+            Err(e) => return Err(Error::InternalError(format!("error reading control: {e}"))),
+        }
+        let mut message = vec![0; u32::from_le_bytes(length) as usize];
+        check!(
+            self.control.read_exact(&mut message),
+            "error reading control: {}"
+        );
+        decode_message(&message)
+    }
+}
+
+/// The event loop's handle on a running worker thread.
+pub struct Worker {
+    output: File,
+    control: File,
+    // bytes read from the output pipe which don't make up a whole message yet
+    buffer: Vec<u8>,
+}
+
+impl Worker {
+    /// Start a worker thread to run the request.
+    pub fn spawn(request: Request, limits: Limits, slot: queue::Slot) -> Result<Self, Error> {
+        let (output_r, output_w) = check!(pipe2(OFlag::O_CLOEXEC), "error creating pipe: {}");
+        // this is safe because File takes ownership of the fds
+        let (output_r, output_w) =
+            unsafe { (File::from_raw_fd(output_r), File::from_raw_fd(output_w)) };
+        let (control_r, control_w) = check!(pipe2(OFlag::O_CLOEXEC), "error creating pipe: {}");
+        let (control_r, control_w) =
+            unsafe { (File::from_raw_fd(control_r), File::from_raw_fd(control_w)) };
+        for fd in [output_r.as_raw_fd(), control_w.as_raw_fd()] {
+            check!(
+                fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)),
+                "error setting O_NONBLOCK on worker pipe: {}"
+            );
+        }
+        let connection = Connection { output: output_w };
+        let control = Control { control: control_r };
+        check!(
+            std::thread::Builder::new()
+                .name("worker".to_string())
+                .spawn(move || run(request, limits, connection, control, slot)),
+            "error starting worker thread: {}"
+        );
+        Ok(Self {
+            output: output_r,
+            control: control_w,
+            buffer: vec![],
+        })
+    }
+
+    /// the file descriptor which becomes readable when the worker has sent something
+    pub fn output_fd(&self) -> RawFd {
+        self.output.as_raw_fd()
+    }
+
+    /// pass a control message from the client on to the worker
+    pub fn control(&mut self, message: ControlMessage) -> Result<(), Error> {
+        let message = encode_message(message)?;
+        // control messages are much smaller than PIPE_BUF, so they are always written whole. If the
+        // pipe is full, the worker isn't reading it, so it doesn't matter if some are dropped
+        match self.control.write_all(&frame(&message)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            // the worker has already finished, so will not be reading it
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+            Err(e) => Err(Error::InternalError(format!(
+                "error writing to worker: {e}"
+            ))),
+        }
+    }
+
+    /// Read the messages the worker has sent so far. The second value is true once the worker has
+    /// closed its end of the pipe, so there will be no more.
+    pub fn read(&mut self) -> Result<(Vec<FromWorker>, bool), Error> {
+        let mut closed = false;
+        let mut buf = [0; 16384];
+        loop {
+            match self.output.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    return Err(Error::InternalError(format!(
+                        "error reading from worker: {e}"
+                    )));
+                }
+            }
+        }
+        let mut messages = vec![];
+        let mut start = 0;
+        while let Some(length) = self.buffer.get(start..start + 4) {
+            let end = start + 4 + u32::from_le_bytes(length.try_into().unwrap()) as usize;
+            let Some(message) = self.buffer.get(start + 4..end) else {
+                break;
+            };
+            messages.push(check!(
+                rmp_serde::from_slice(message),
+                "error decoding message from worker: {}"
+            ));
+            start = end;
+        }
+        self.buffer.drain(..start);
+        Ok((messages, closed))
+    }
+}
+
+fn run(
+    request: Request,
+    limits: Limits,
+    mut connection: Connection,
+    mut control: Control,
+    slot: queue::Slot,
+) {
+    let result = invoke(&request, &limits, &mut connection, &mut control);
+    // the sandbox has been stopped and cleaned up by now, so it no longer counts as running
+    drop(slot);
+    match connection.send(FromWorker::Finished(result)) {
+        Ok(()) | Err(Error::ClientWentAway) => (),
+        Err(e) => eprintln!("error sending result from worker: {e:?}"),
+    }
+}
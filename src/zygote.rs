@@ -0,0 +1,688 @@
+//! The zygote is a small process, forked from the main server process at startup before it has any
+//! other threads, which creates sandboxes on behalf of the rest of the server.
+//!
+//! clone3 is only safe to call from a single-threaded process, so doing it here leaves the network
+//! code free to use threads. Each sandbox is controlled through its own socket, over which the
+//! zygote sends back the sandbox's pidfd and output pipes, and later its exit status. Nothing the
+//! zygote does for one sandbox waits for another: stopped sandboxes are reaped once their pidfds say
+//! they have exited, and their cgroups are removed by retrying between other work.
+//!
+//! The zygote is also the only process which keeps the privileges needed to create sandboxes. Once
+//! it has been forked, the network-facing process drops its capabilities and, if
+//! `$ATO_NETWORK_USER` is set, switches to that user, so that it can't create cgroups itself. It can
+//! only send the zygote launch commands, which the zygote checks before acting on them.
+//!
+//! When the server is upgraded, the old zygote starts a new one from the new binary (so that changes
+//! to the sandbox take effect), which the new version of the server uses from then on. The old
+//! zygote exits once the old version's sandboxes have finished.
+
+use crate::{
+    Error, Limits, Request, auth, check,
+    constants::*,
+    ids,
+    languages::{self, Language},
+    lifecycle,
+    sandbox::{self, Sandbox},
+};
+use capctl::{caps, prctl};
+use nix::{
+    libc,
+    poll::{PollFd, PollFlags, poll},
+    sys::{
+        signal::{SigHandler, SigSet, Signal, signal},
+        socket::{
+            AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, Shutdown, SockFlag,
+            SockType, recvmsg, sendmsg, shutdown, socketpair,
+        },
+    },
+    unistd::{ForkResult, User, close, fork, setgroups, setresgid, setresuid},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{LazyLock, Mutex};
+
+/// the main process's end of the socket to the zygote, until it is closed by shut_down
+static SOCKET: Mutex<Option<OwnedFd>> = Mutex::new(None);
+
+/// how often to try again to remove the cgroups of stopped sandboxes, in milliseconds
+const CGROUP_REMOVE_INTERVAL: i32 = 5;
+
+/// big enough for any message, including a request of the maximum size
+const MAX_MESSAGE_SIZE: usize = 2 * MAX_REQUEST_SIZE;
+
+/// The commands the zygote accepts, each sent along with a socket for the reply.
+#[derive(Serialize, Deserialize)]
+enum Command {
+    /// create a sandbox, which is then controlled through the socket
+    Launch(Launch),
+    /// read the languages file again (see languages.rs), replying with a `Result<(), Error>`
+    ReloadLanguages,
+    /// start a new zygote from the server binary, which may have been upgraded, replying with a
+    /// `Result<(), Error>` along with the socket to the new zygote
+    Replace,
+}
+
+/// Every field is required, and the zygote checks them all again, since the process sending it
+/// isn't trusted.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Launch {
+    language: String,
+    code: ByteBuf,
+    custom_runner: Option<ByteBuf>,
+    input: ByteBuf,
+    arguments: Vec<ByteBuf>,
+    options: Vec<ByteBuf>,
+    /// in seconds
+    timeout: i32,
+    /// in bytes
+    memory: u64,
+    pids: u64,
+    unprivileged_user: Option<bool>,
+    deterministic: bool,
+    egress: bool,
+    version: Option<String>,
+}
+
+impl Launch {
+    fn new(request: &Request, limits: &Limits) -> Self {
+        Self {
+            language: request.language.clone(),
+            code: request.code.clone(),
+            custom_runner: request.custom_runner.clone(),
+            input: request.input.clone(),
+            arguments: request.arguments.clone(),
+            options: request.options.clone(),
+            timeout: limits.timeout,
+            memory: limits.memory,
+            pids: limits.pids,
+            unprivileged_user: request.unprivileged_user,
+            deterministic: request.deterministic,
+            egress: limits.egress.is_some(),
+            version: request.version.clone(),
+        }
+    }
+
+    /// Make sure the command is within the limits any client could have been given.
+    fn check(self) -> Result<(Request, &'static Language, Limits), String> {
+        let language = languages::get()
+            .get(&self.language)
+            .ok_or_else(|| format!("no such language: {}", self.language))?;
+        if language
+            .version(&self.language, self.version.as_deref())
+            .is_none()
+        {
+            return Err(format!("no such version: {:?}", self.version));
+        }
+        let language_limits = &language.limits;
+        let (max_timeout, max_memory, max_pids) = auth::max_limits(language_limits);
+        if self.timeout < 1 || self.timeout > max_timeout {
+            return Err(format!("timeout out of range: {}", self.timeout));
+        }
+        if self.memory < 1 || self.memory > max_memory * MiB {
+            return Err(format!("memory limit out of range: {}", self.memory));
+        }
+        if self.pids < 1 || self.pids > max_pids {
+            return Err(format!("pids limit out of range: {}", self.pids));
+        }
+        if self
+            .options
+            .iter()
+            .chain(&self.arguments)
+            .any(|arg| arg.contains(&0))
+        {
+            return Err("argument contains null byte".to_string());
+        }
+        let size = self.code.len()
+            + self.input.len()
+            + self.custom_runner.as_ref().map_or(0, |runner| runner.len())
+            + self
+                .options
+                .iter()
+                .chain(&self.arguments)
+                .map(|arg| arg.len())
+                .sum::<usize>();
+        if size > MAX_REQUEST_SIZE {
+            return Err(format!("request too large: {size}"));
+        }
+        // the proxy is run by the network process, so the policy itself doesn't matter here, as long
+        // as some client could have been given one
+        let egress = if self.egress {
+            let policy = language
+                .egress
+                .as_ref()
+                .or_else(|| auth::KEYS.values().find_map(|key| key.egress.as_ref()))
+                .ok_or("egress not allowed")?;
+            Some(policy)
+        } else {
+            None
+        };
+        let limits = Limits {
+            timeout: self.timeout,
+            memory: self.memory,
+            pids: self.pids,
+            output: (language_limits.output * KiB) as usize,
+            tmpfs: language_limits.tmpfs * KiB,
+            file_size: language_limits.file_size * MiB,
+            egress,
+        };
+        let request = Request {
+            language: self.language,
+            code: self.code,
+            custom_runner: self.custom_runner,
+            input: self.input,
+            arguments: self.arguments,
+            options: self.options,
+            timeout: Some(self.timeout),
+            memory: Some(self.memory / MiB),
+            pids: Some(self.pids),
+            unprivileged_user: self.unprivileged_user,
+            deterministic: self.deterministic,
+            version: self.version,
+        };
+        Ok((request, language, limits))
+    }
+}
+
+/// sent back by the zygote once it has tried to create the sandbox
+#[derive(Serialize, Deserialize)]
+enum Started {
+    /// the sandbox's pidfd, stdout and stderr are attached, in that order, followed by the socket
+    /// its egress proxy listener is sent over, if it has one
+    Ok,
+    Err(Error),
+}
+
+/// sent back by the zygote once the sandbox has been stopped
+#[derive(Serialize, Deserialize)]
+pub struct Stopped {
+    /// as returned by wait(2)
+    pub wait_status: i32,
+    pub usage: Usage,
+    /// names of the system calls the seccomp filter denied
+    pub blocked_syscalls: Vec<String>,
+}
+
+/// resource usage of a sandbox's main process
+#[derive(Serialize, Deserialize)]
+pub struct Usage {
+    /// in nanoseconds
+    pub kernel: i64,
+    /// in nanoseconds
+    pub user: i64,
+    /// in KiB
+    pub max_mem: i64,
+    pub waits: i64,
+    pub preemptions: i64,
+    pub major_page_faults: i64,
+    pub minor_page_faults: i64,
+    pub input_ops: i64,
+    pub output_ops: i64,
+}
+
+impl From<libc::rusage> for Usage {
+    fn from(usage: libc::rusage) -> Self {
+        let nanos = |time: libc::timeval| time.tv_sec * 1_000_000_000 + time.tv_usec * 1000;
+        Self {
+            kernel: nanos(usage.ru_stime),
+            user: nanos(usage.ru_utime),
+            max_mem: usage.ru_maxrss,
+            waits: usage.ru_nvcsw,
+            preemptions: usage.ru_nivcsw,
+            major_page_faults: usage.ru_majflt,
+            minor_page_faults: usage.ru_minflt,
+            input_ops: usage.ru_inblock,
+            output_ops: usage.ru_oublock,
+        }
+    }
+}
+
+pub fn seqpacket_pair() -> nix::Result<(OwnedFd, OwnedFd)> {
+    let (a, b) = socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?;
+    // this is safe because the fds are new and nothing else owns them
+    Ok(unsafe { (OwnedFd::from_raw_fd(a), OwnedFd::from_raw_fd(b)) })
+}
+
+fn send(socket: RawFd, message: &impl Serialize, fds: &[RawFd]) -> Result<(), Error> {
+    let message = check!(
+        rmp_serde::to_vec_named(message),
+        "error encoding message for zygote: {}"
+    );
+    let rights = [ControlMessage::ScmRights(fds)];
+    let cmsgs = if fds.is_empty() { &[][..] } else { &rights[..] };
+    check!(
+        sendmsg::<()>(
+            socket,
+            &[IoSlice::new(&message)],
+            cmsgs,
+            MsgFlags::empty(),
+            None
+        ),
+        "error sending message to zygote: {}"
+    );
+    Ok(())
+}
+
+/// a message and any fds sent with it, or None if the other end has closed the socket
+type Received<T> = Option<(T, Vec<OwnedFd>)>;
+
+fn receive<T: DeserializeOwned>(socket: RawFd) -> Result<Received<T>, Error> {
+    let Some((message, fds)) = receive_raw(socket)? else {
+        return Ok(None);
+    };
+    let message = check!(
+        rmp_serde::from_slice(&message),
+        "error decoding message from zygote: {}"
+    );
+    Ok(Some((message, fds)))
+}
+
+fn receive_raw(socket: RawFd) -> Result<Received<Vec<u8>>, Error> {
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    let mut cmsg_space = nix::cmsg_space!([RawFd; 4]);
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let msg = check!(
+        recvmsg::<()>(
+            socket,
+            &mut iov,
+            Some(&mut cmsg_space),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ),
+        "error receiving message from zygote: {}"
+    );
+    let mut fds = vec![];
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            // this is safe because the fds were just received, so nothing else owns them
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    let (bytes, flags) = (msg.bytes, msg.flags);
+    if flags.intersects(MsgFlags::MSG_TRUNC | MsgFlags::MSG_CTRUNC) {
+        return Err(Error::InternalError(
+            "message from zygote was truncated".to_string(),
+        ));
+    }
+    if bytes == 0 {
+        return Ok(None);
+    }
+    buf.truncate(bytes);
+    Ok(Some((buf, fds)))
+}
+
+/// Fork the zygote, and then drop the privileges this process no longer needs. Must be called
+/// before the main process starts any threads, and before it opens anything the zygote shouldn't
+/// have.
+///
+/// After an upgrade, the new version of the server uses the zygote which the old one had started for
+/// it instead.
+pub fn start() {
+    if let Some(fd) = lifecycle::take_inherited_fd(lifecycle::LAUNCHER_FD_VAR) {
+        // this is safe because the fd was passed on by the previous version, and nothing else owns it
+        *SOCKET.lock().unwrap() = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        return;
+    }
+    let (ours, theirs) = seqpacket_pair().expect("error creating zygote socket");
+    // this is safe because there are no other threads yet
+    match unsafe { fork() }.expect("error forking zygote") {
+        ForkResult::Child => {
+            drop(ours);
+            ids::init();
+            run(theirs)
+        }
+        ForkResult::Parent { .. } => {
+            drop(theirs);
+            *SOCKET.lock().unwrap() = Some(ours);
+            drop_privileges();
+        }
+    }
+}
+
+/// Give up everything the network-facing process doesn't need, which is anything beyond what an
+/// unprivileged user can do, and optionally the user ID which owns the cgroups.
+fn drop_privileges() {
+    match std::env::var("ATO_NETWORK_USER") {
+        Ok(name) => {
+            let user = User::from_name(&name)
+                .expect("error looking up $ATO_NETWORK_USER")
+                .unwrap_or_else(|| panic!("$ATO_NETWORK_USER does not exist: {name}"));
+            setgroups(&[]).expect("error dropping supplementary groups");
+            setresgid(user.gid, user.gid, user.gid).expect("error switching group");
+            setresuid(user.uid, user.uid, user.uid).expect("error switching user");
+        }
+        Err(std::env::VarError::NotPresent) => (),
+        Err(std::env::VarError::NotUnicode(_)) => panic!("$ATO_NETWORK_USER is invalid Unicode"),
+    }
+    caps::ambient::clear().expect("error dropping ambient capabilities");
+    // the bounding set can't be cleared without CAP_SETPCAP, which the server isn't usually given,
+    // but NO_NEW_PRIVS stops it mattering anyway
+    caps::CapState::empty()
+        .set_current()
+        .expect("error dropping capabilities");
+    prctl::set_no_new_privs().expect("error setting NO_NEW_PRIVS flag");
+}
+
+/// If this process was started as a new zygote by the old one (see replace), become the zygote.
+/// Must be called before anything else.
+pub fn resume() {
+    let Some(fd) = lifecycle::take_inherited_fd(lifecycle::ZYGOTE_FD_VAR) else {
+        return;
+    };
+    // this is safe because the fd was passed on by the old zygote, and nothing else owns it
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // the configuration the forked zygote would have had from the main process
+    LazyLock::force(&auth::KEYS);
+    ids::init();
+    languages::load();
+    run(socket)
+}
+
+/// Tell the zygote not to create any more sandboxes, and wait for it to exit once its sandboxes have
+/// all stopped.
+pub fn shut_down() {
+    let Some(socket) = SOCKET.lock().unwrap().take() else {
+        return;
+    };
+    if let Err(e) = shutdown(socket.as_raw_fd(), Shutdown::Write) {
+        eprintln!("error shutting down zygote: {e}");
+        return;
+    }
+    // the zygote never sends anything on this socket, so the only thing to receive is it closing
+    while let Ok(Some(_)) = receive_raw(socket.as_raw_fd()) {}
+}
+
+fn run(socket: OwnedFd) -> ! {
+    // the zygote doesn't need anything else the main process had open
+    let keep: Vec<RawFd> = std::iter::once(socket.as_raw_fd())
+        .chain(ids::fd())
+        .collect();
+    // this is safe because nothing else in this process uses the other fds
+    unsafe { close_fds::close_open_fds(3, &keep) };
+    // tell the kernel that we now *do* care about our child processes
+    // see waitpid(2) § NOTES
+    // this is safe because there was no previous signal handler function
+    unsafe { signal(Signal::SIGCHLD, SigHandler::SigDfl) }.unwrap();
+    // the server may have been given SETUID and SETGID so the network process can switch to
+    // $ATO_NETWORK_USER. The zygote only needs them to map sandboxes to their own IDs. They are
+    // kept in the ambient set, so that the zygote started by replace has them too (sandboxes drop
+    // them in drop_caps)
+    if ids::RANGE.is_some() {
+        // otherwise sandboxes would keep the server's supplementary groups outside the container
+        setgroups(&[]).expect("error dropping supplementary groups");
+    } else {
+        let mut state = caps::CapState::get_current().expect("error getting capabilities");
+        for set in [
+            &mut state.effective,
+            &mut state.permitted,
+            &mut state.inheritable,
+        ] {
+            set.drop_all([caps::Cap::SETUID, caps::Cap::SETGID]);
+        }
+        state.set_current().expect("error dropping capabilities");
+    }
+
+    let mut jobs: HashMap<RawFd, (OwnedFd, Sandbox)> = HashMap::new();
+    // killed sandboxes, keyed by pidfd, whose main processes haven't exited yet
+    let mut stopping: HashMap<RawFd, (OwnedFd, Sandbox)> = HashMap::new();
+    // stopped sandboxes whose cgroups haven't finished dying yet
+    let mut leftovers: Vec<sandbox::Leftovers> = vec![];
+    let mut accepting = true;
+    while accepting || !jobs.is_empty() || !stopping.is_empty() || !leftovers.is_empty() {
+        let mut poll_args = vec![];
+        if accepting {
+            poll_args.push(PollFd::new(socket.as_raw_fd(), PollFlags::POLLIN));
+        }
+        let job_fds: Vec<RawFd> = jobs.keys().copied().collect();
+        poll_args.extend(job_fds.iter().map(|&fd| PollFd::new(fd, PollFlags::POLLIN)));
+        let pidfds: Vec<RawFd> = stopping.keys().copied().collect();
+        poll_args.extend(pidfds.iter().map(|&fd| PollFd::new(fd, PollFlags::POLLIN)));
+        // the jobs whose seccomp filters need watching, after everything else
+        let mut monitored = vec![];
+        for (&job_fd, (_, sandbox)) in &jobs {
+            if let Some(fd) = sandbox.seccomp.fd() {
+                monitored.push(job_fd);
+                poll_args.push(PollFd::new(fd, PollFlags::POLLIN));
+            }
+        }
+        // cgroups are removed by polling, since the kernel doesn't say when they can be
+        let timeout = if leftovers.is_empty() {
+            -1
+        } else {
+            CGROUP_REMOVE_INTERVAL
+        };
+        match poll(&mut poll_args, timeout) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => (),
+            Err(e) => panic!("zygote: error polling: {e}"),
+        }
+        let is_ready = |poll: &PollFd| poll.revents().is_some_and(|events| !events.is_empty());
+        let (poll_args, seccomp_polls) = poll_args.split_at(poll_args.len() - monitored.len());
+        let (poll_args, pidfd_polls) = poll_args.split_at(poll_args.len() - pidfds.len());
+
+        // answer denied system calls first, since the sandbox is waiting for them
+        for (job_fd, poll) in monitored.iter().zip(seccomp_polls) {
+            if let Some(events) = poll.revents().filter(|events| !events.is_empty()) {
+                jobs.get_mut(job_fd).unwrap().1.seccomp.handle(events);
+            }
+        }
+        for (pidfd, poll) in pidfds.iter().zip(pidfd_polls) {
+            if !is_ready(poll) {
+                continue;
+            }
+            let (job_socket, sandbox) = stopping.remove(pidfd).unwrap();
+            match sandbox.finish() {
+                Ok((stopped, rest)) => {
+                    // the job may have gone away already, in which case nobody needs to know
+                    let _ = send(job_socket.as_raw_fd(), &stopped, &[]);
+                    leftovers.push(rest);
+                }
+                Err(e) => eprintln!("zygote: {e:?}"),
+            }
+        }
+        leftovers.retain_mut(|leftovers| !leftovers.clean_up());
+        if accepting && is_ready(&poll_args[0]) {
+            match handle_command(socket.as_raw_fd()) {
+                Ok(Some((job_socket, sandbox))) => {
+                    jobs.insert(job_socket.as_raw_fd(), (job_socket, sandbox));
+                }
+                Ok(None) => (),
+                // the main process has closed its end, so no more sandboxes will be needed
+                Err(None) => accepting = false,
+                Err(Some(e)) => eprintln!("zygote: {e:?}"),
+            }
+        }
+        let job_polls = &poll_args[poll_args.len() - job_fds.len()..];
+        for (fd, poll) in job_fds.iter().zip(job_polls) {
+            if !is_ready(poll) {
+                continue;
+            }
+            // anything from the job's socket, including it being closed, means stop the sandbox.
+            // Its exit status is sent once its main process has exited, rather than waiting here
+            let (job_socket, sandbox) = jobs.remove(fd).unwrap();
+            match sandbox.kill() {
+                Ok(()) => {
+                    stopping.insert(sandbox.pidfd, (job_socket, sandbox));
+                }
+                Err(e) => {
+                    eprintln!("zygote: {e:?}");
+                    // dropping it tries again to kill and remove the cgroup
+                    let _ = close(sandbox.pidfd);
+                }
+            }
+        }
+    }
+    std::process::exit(0)
+}
+
+/// Carry out a command from the main process. If it created a sandbox, returns the socket which
+/// controls it.
+///
+/// Returns Err(None) if the main process has closed its end of the socket.
+fn handle_command(socket: RawFd) -> Result<Option<(OwnedFd, Sandbox)>, Option<Error>> {
+    let Some((message, mut fds)) = receive_raw(socket)? else {
+        return Err(None);
+    };
+    // anything else sent with it is closed when dropped
+    if fds.len() != 1 {
+        return Err(Some(Error::InternalError(
+            "command without exactly one socket".to_string(),
+        )));
+    }
+    let job_socket = fds.pop().unwrap();
+    let result = match rmp_serde::from_slice(&message) {
+        Ok(Command::Launch(launch)) => Launch::check(launch),
+        Ok(Command::Replace) => {
+            // our end of the new zygote's socket is closed once it has been sent
+            let (result, socket) = match spawn_replacement() {
+                Ok(socket) => (Ok(()), Some(socket)),
+                Err(e) => (Err(e), None),
+            };
+            let fds: Vec<RawFd> = socket.iter().map(|socket| socket.as_raw_fd()).collect();
+            send(job_socket.as_raw_fd(), &result, &fds)?;
+            return Ok(None);
+        }
+        Ok(Command::ReloadLanguages) => {
+            let result = languages::reload_in_zygote();
+            if let Err(e) = &result {
+                eprintln!("zygote: error reloading languages: {e:?}");
+            }
+            send(job_socket.as_raw_fd(), &result, &[])?;
+            return Ok(None);
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    let result = result
+        .map_err(|e| Error::InternalError(format!("invalid launch command: {e}")))
+        .and_then(|(request, language, limits)| sandbox::spawn(&request, language, &limits));
+    match result {
+        Ok(mut sandbox) => {
+            let fds = [sandbox.pidfd, sandbox.stdout_r, sandbox.stderr_r];
+            let egress_r = sandbox.egress_r.take();
+            let all_fds: Vec<_> = fds
+                .into_iter()
+                .chain(egress_r.as_ref().map(|fd| fd.as_raw_fd()))
+                .collect();
+            let sent = send(job_socket.as_raw_fd(), &Started::Ok, &all_fds);
+            // the job has its own copies now (egress_r is closed when it's dropped). The pidfd is
+            // kept to know when the sandbox's main process has exited after it has been killed
+            for fd in [sandbox.stdout_r, sandbox.stderr_r] {
+                let _ = close(fd);
+            }
+            if let Err(e) = sent {
+                // nobody is going to ask for it to be stopped
+                let _ = sandbox.stop();
+                return Err(Some(e));
+            }
+            Ok(Some((job_socket, sandbox)))
+        }
+        Err(e) => {
+            send(job_socket.as_raw_fd(), &Started::Err(e), &[])?;
+            Ok(None)
+        }
+    }
+}
+
+/// Start a new zygote from the server binary, returning the socket to it.
+fn spawn_replacement() -> Result<OwnedFd, Error> {
+    let (ours, theirs) = check!(seqpacket_pair(), "error creating zygote socket: {}");
+    let theirs = [theirs.as_raw_fd()];
+    let ids: Vec<RawFd> = ids::fd().into_iter().collect();
+    let mut fds = vec![(lifecycle::ZYGOTE_FD_VAR, &theirs[..])];
+    if !ids.is_empty() {
+        fds.push((lifecycle::IDS_FD_VAR, &ids));
+    }
+    check!(
+        lifecycle::spawn_binary(std::iter::empty(), &fds, SigSet::empty()),
+        "error starting new zygote: {}"
+    );
+    Ok(ours)
+}
+
+/// A running sandbox, as seen from the rest of the server.
+pub struct Job {
+    socket: OwnedFd,
+    pub pidfd: RawFd,
+    pub stdout_r: RawFd,
+    pub stderr_r: RawFd,
+    /// receives the egress proxy's listener from the sandbox
+    pub egress_r: Option<RawFd>,
+    // keeps the fds above open
+    _fds: Vec<OwnedFd>,
+}
+
+/// Send a command to the zygote, returning the socket its reply will come on.
+fn send_command(command: &Command) -> Result<OwnedFd, Error> {
+    let (ours, theirs) = check!(seqpacket_pair(), "error creating job socket: {}");
+    let socket = SOCKET.lock().unwrap();
+    let Some(socket) = socket.as_ref() else {
+        return Err(Error::InternalError("zygote has been stopped".to_string()));
+    };
+    send(socket.as_raw_fd(), command, &[theirs.as_raw_fd()])?;
+    Ok(ours)
+}
+
+/// Ask the zygote to create a sandbox running the request.
+pub fn spawn(request: &Request, limits: &Limits) -> Result<Job, Error> {
+    let ours = send_command(&Command::Launch(Launch::new(request, limits)))?;
+    let expected_fds = if limits.egress.is_some() { 4 } else { 3 };
+    match receive::<Started>(ours.as_raw_fd())? {
+        Some((Started::Ok, fds)) if fds.len() == expected_fds => Ok(Job {
+            socket: ours,
+            pidfd: fds[0].as_raw_fd(),
+            stdout_r: fds[1].as_raw_fd(),
+            stderr_r: fds[2].as_raw_fd(),
+            egress_r: fds.get(3).map(|fd| fd.as_raw_fd()),
+            _fds: fds,
+        }),
+        Some((Started::Ok, _)) => Err(Error::InternalError(
+            "zygote sent the wrong number of fds".to_string(),
+        )),
+        Some((Started::Err(e), _)) => Err(e),
+        None => Err(Error::InternalError("zygote went away".to_string())),
+    }
+}
+
+/// Ask the zygote to start a new zygote from the server binary, for a new version of the server to
+/// use after an upgrade. Returns the socket to the new zygote.
+pub fn replace() -> Result<OwnedFd, Error> {
+    let ours = send_command(&Command::Replace)?;
+    match receive::<Result<(), Error>>(ours.as_raw_fd())? {
+        Some((Ok(()), mut fds)) if fds.len() == 1 => Ok(fds.pop().unwrap()),
+        Some((Ok(()), _)) => Err(Error::InternalError(
+            "zygote sent the wrong number of fds".to_string(),
+        )),
+        Some((Err(e), _)) => Err(e),
+        None => Err(Error::InternalError("zygote went away".to_string())),
+    }
+}
+
+/// Ask the zygote to read the languages file again.
+pub fn reload_languages() -> Result<(), Error> {
+    let ours = send_command(&Command::ReloadLanguages)?;
+    match receive(ours.as_raw_fd())? {
+        Some((result, _)) => result,
+        None => Err(Error::InternalError("zygote went away".to_string())),
+    }
+}
+
+impl Job {
+    /// kill the sandbox, and get its exit status and resource usage
+    pub fn stop(&self) -> Result<Stopped, Error> {
+        send(self.socket.as_raw_fd(), &(), &[])?;
+        match receive(self.socket.as_raw_fd())? {
+            Some((stopped, _)) => Ok(stopped),
+            None => Err(Error::InternalError("zygote went away".to_string())),
+        }
+    }
+}
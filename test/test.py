@@ -1,5 +1,7 @@
 from asyncio import gather, get_event_loop, sleep
 from contextlib import asynccontextmanager
+from http.server import HTTPServer, BaseHTTPRequestHandler
+from threading import Thread
 from os import environ
 import subprocess
 from time import monotonic
@@ -22,6 +24,10 @@ url = environ["URL"]
 
 REMOTE = bool(environ.get("REMOTE"))
 
+# an API key whose egress policy allows localhost:$EGRESS_PORT, for testing the egress proxy against a stand-in server
+EGRESS_API_KEY = environ.get("EGRESS_API_KEY")
+EGRESS_PORT = int(environ.get("EGRESS_PORT") or 8601)
+
 FAST = int(environ.get("FAST") or 0)
 slow = mark.skipif(FAST >= 2, reason="environment variable FAST set >= 2")
 very_slow = mark.skipif(FAST >= 1, reason="environment variable FAST set >= 1")
@@ -78,12 +84,18 @@ async def test_basic_execution(c):
     assert 0 <= r.pop("minor_page_faults") < 10000
     assert 0 <= r.pop("input_ops") < 100000
     assert 0 <= r.pop("output_ops") < 100
+    # zsh doesn't name its versions
+    assert r.pop("version") is None
+    # only known once the version probe has finished, which it may not have yet
+    detected_version = r.pop("detected_version")
+    assert detected_version is None or detected_version.startswith("zsh ")
     assert r == {
         "timed_out": False,
         "stdout_truncated": False,
         "stderr_truncated": False,
         "status_type": "exited",
         "status_value": 0,
+        "blocked_syscalls": [],
     }
 
 
@@ -128,6 +140,33 @@ async def test_custom_runner(c):
     assert loads(await c.recv()).keys() == {"Done"}
 
 
+async def test_unprivileged_user(c):
+    runner = "/ATO/unprivileged id -u; /ATO/unprivileged touch /ATO/foo"
+    await c.send(req("", custom_runner=runner, hook=lambda d: d.update(unprivileged_user=True)))
+    assert loads(await c.recv())["Stdout"] == b"65534\n"
+    assert b"Permission denied" in loads(await c.recv())["Stderr"]
+
+
+async def test_no_unprivileged_user(c):
+    await c.send(req("", custom_runner="/ATO/unprivileged id -u"))
+    assert b"unprivileged: setresgid" in loads(await c.recv())["Stderr"]
+
+
+async def test_deterministic(c):
+    runner = "cut -d. -f1 /proc/uptime; echo $TZ $SOURCE_DATE_EPOCH $ATO_SEED"
+    await c.send(req("", custom_runner=runner, hook=lambda d: d.update(deterministic=True)))
+    uptime = loads(await c.recv())["Stdout"]
+    assert int(uptime) < 10
+    assert loads(await c.recv())["Stdout"] == b"UTC 946684800 0\n"
+
+
+@very_slow
+async def test_language_alias(c):
+    await c.send(req("import sys; print(sys.version_info[0])", language="python3"))
+    assert loads(await c.recv())["Stdout"] == b"3\n"
+    assert loads(await c.recv()).keys() == {"Done"}
+
+
 async def test_stderr(c):
     await c.send(req("echo hello >&2"))
     assert loads(await c.recv()) == {"Stderr": b"hello\n"}
@@ -188,6 +227,7 @@ async def _test_error(msg, code=POLICY_VIOLATION, max_time=0.1):
     ({"timeout": -4}, "invalid request: timeout not in range 1-60: -4"),
     ({"language": "doesntexist"}, "invalid request: no such language: doesntexist"),
     ({"language": "ZSH"}, "invalid request: no such language: ZSH"),
+    ({"hook": lambda d: d.update(version="doesntexist")}, "invalid request: no such version of zsh: doesntexist"),
     ({"arguments": ["null\0byte"]}, "invalid request: argument contains null byte"),
     ({"options": ["null\0byte"]}, "invalid request: argument contains null byte"),
 ))
@@ -395,6 +435,65 @@ async def test_loopback(c):
     await c.send(req("ip addr"))
     output = loads(await c.recv())["Stdout"]
     assert b"lo: <LOOPBACK,UP,LOWER_UP>" in output
+    assert b"inet 127.0.0.1/8" in output
+    assert b"inet6 ::1/128" in output
+
+
+async def test_hostname(c):
+    await c.send(req("uname -n; grep -c ato /etc/hosts"))
+    assert loads(await c.recv())["Stdout"] == b"ato\n2\n"
+
+
+async def test_name_lookup_fails_quickly(c):
+    start = monotonic()
+    await c.send(req("getent hosts example.com; echo $?"))
+    assert loads(await c.recv())["Stdout"] == b"2\n"
+    assert monotonic() - start < 1
+
+
+async def test_no_egress(c):
+    await c.send(req("", custom_runner="echo ${http_proxy-none}; exec 3<>/dev/tcp/127.0.0.1/3128"))
+    assert loads(await c.recv())["Stdout"] == b"none\n"
+    assert b"Connection refused" in loads(await c.recv())["Stderr"]
+
+
+class StandIn(BaseHTTPRequestHandler):
+    def do_GET(self):
+        self.send_response(200)
+        self.end_headers()
+        self.wfile.write(f"stand-in {self.path}".encode())
+
+    def log_message(self, *args):
+        pass
+
+
+@mark.skipif(not EGRESS_API_KEY or REMOTE, reason="environment variable EGRESS_API_KEY not set, or REMOTE set")
+async def test_egress():
+    server = HTTPServer(("localhost", EGRESS_PORT), StandIn)
+    Thread(target=server.serve_forever, daemon=True).start()
+    # the runner is run by /ATO/bash, which can make connections without curl being installed
+    runner = f"""
+        echo $http_proxy
+        for port in {EGRESS_PORT} {EGRESS_PORT + 1}; do
+            exec 3<>/dev/tcp/127.0.0.1/3128
+            printf 'GET http://localhost:%s/hello HTTP/1.0\\r\\n\\r\\n' $port >&3
+            cat <&3
+        done
+    """
+    try:
+        async with connect(url, additional_headers={"Authorization": f"Bearer {EGRESS_API_KEY}"}) as c:
+            await c.send(req("", custom_runner=runner))
+            output = b""
+            async for msg in c:
+                msg = loads(msg)
+                if "Done" in msg:
+                    break
+                output += msg.get("Stdout", b"")
+        assert output.startswith(b"http://127.0.0.1:3128\n")
+        assert b"\r\n\r\nstand-in /hello" in output
+        assert b"403 Forbidden" in output
+    finally:
+        server.shutdown()
 
 
 async def test_tmp(c):
@@ -403,10 +502,15 @@ async def test_tmp(c):
 
 
 async def test_writeable_fs(c):
-    await c.send(req("echo hi > /foo; cat /foo"))
+    await c.send(req("echo hi > /ATO/foo; cat /ATO/foo"))
     assert loads(await c.recv())["Stdout"] == b"hi\n"
 
 
+async def test_readonly_fs(c):
+    await c.send(req("echo hi > /foo"))
+    assert b"permission denied" in loads(await c.recv())["Stderr"].lower()
+
+
 with open("../languages.json") as f:
     hello_world_tests = [
         (lang["hello_world"].pop("output"), lang_id, lang["hello_world"])
@@ -0,0 +1,27 @@
+// Run a command as the unprivileged user inside the sandbox (nobody), for runners which have to do
+// some things (like compiling) as root first. Only works if the language or request enables it.
+#define _GNU_SOURCE
+#include <stdio.h>
+#include <unistd.h>
+
+#define UNPRIVILEGED_ID 65534
+
+int main(int argc, char * argv []) {
+    if (argc < 2) {
+        fprintf(stderr, "%s\n", "unprivileged: too few arguments");
+        return 1;
+    };
+    // the group has to be changed first, because changing the user gives up the right to
+    if (setresgid(UNPRIVILEGED_ID, UNPRIVILEGED_ID, UNPRIVILEGED_ID) < 0) {
+        perror("unprivileged: setresgid");
+        return 1;
+    };
+    if (setresuid(UNPRIVILEGED_ID, UNPRIVILEGED_ID, UNPRIVILEGED_ID) < 0) {
+        perror("unprivileged: setresuid");
+        return 1;
+    };
+    execvp(argv[1], argv + 1);
+    // shouldn't reach this point if execvp succeeds
+    perror("unprivileged: execvp");
+    return 1;
+};